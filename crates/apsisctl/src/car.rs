@@ -0,0 +1,573 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reconstruct the UnixFS files stored in an IPFS CAR (Content Addressable
+//! aRchive), so that they can be encoded again with ERIS. Only what is needed
+//! to recover file contents is parsed, and sharded directories are not
+//! supported. Blocks are checked against their CIDs as they are read.
+
+use anyhow::{Result, anyhow, bail};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Pragma opening a CARv2 file, which wraps a CARv1 payload.
+const CAR_V2_PRAGMA: &[u8] = &[
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+
+/// Multicodec of IPLD raw blocks, holding file data directly.
+const RAW: u64 = 0x55;
+
+/// Multicodec of DAG-PB blocks, holding UnixFS nodes.
+const DAG_PB: u64 = 0x70;
+
+/// Multihash functions blocks can be checked with.
+const IDENTITY: u64 = 0x00;
+const SHA2_256: u64 = 0x12;
+const BLAKE2B_256: u64 = 0xb220;
+
+/// CBOR tag of the CIDs in a CAR header.
+const CID_TAG: u64 = 42;
+
+/// How deeply nodes are followed, so that a crafted CAR file can't exhaust
+/// the stack.
+const MAX_DEPTH: usize = 128;
+
+/// UnixFS node types.
+const UNIXFS_RAW: u64 = 0;
+const UNIXFS_DIRECTORY: u64 = 1;
+const UNIXFS_FILE: u64 = 2;
+
+/// A block's multicodec and data.
+struct Block<'a> {
+    codec: u64,
+    data: &'a [u8],
+}
+
+/// Content of a CAR's root.
+pub enum Root {
+    File(Vec<u8>),
+    /// Files keyed by their path within the root directory.
+    Directory(Vec<(String, Vec<u8>)>),
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| anyhow!("Truncated varint."))?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Varint is too long.")
+}
+
+fn read_bytes<'a>(buf: &mut &'a [u8], length: u64) -> Result<&'a [u8]> {
+    let length = usize::try_from(length)?;
+    if buf.len() < length {
+        bail!("Truncated CAR file.");
+    }
+    let (bytes, rest) = buf.split_at(length);
+    *buf = rest;
+    Ok(bytes)
+}
+
+/// A binary CID: its bytes, the multicodec of its block, and the multihash
+/// function and digest the block must match.
+struct Cid<'a> {
+    bytes: &'a [u8],
+    codec: u64,
+    hash: u64,
+    digest: &'a [u8],
+}
+
+impl<'a> Cid<'a> {
+    fn read(buf: &mut &'a [u8]) -> Result<Self> {
+        let start = *buf;
+        // A CIDv0 is a bare SHA-256 multihash of a DAG-PB block
+        let (codec, hash, digest) = if buf.starts_with(&[0x12, 0x20]) {
+            (DAG_PB, SHA2_256, &read_bytes(buf, 34)?[2..])
+        } else {
+            if read_varint(buf)? != 1 {
+                bail!("Unsupported CID version.");
+            }
+            let codec = read_varint(buf)?;
+            let hash = read_varint(buf)?;
+            let length = read_varint(buf)?;
+            (codec, hash, read_bytes(buf, length)?)
+        };
+        Ok(Self {
+            bytes: &start[..start.len() - buf.len()],
+            codec,
+            hash,
+            digest,
+        })
+    }
+
+    /// Whether `data` is the block this CID refers to.
+    fn matches(&self, data: &[u8]) -> Result<bool> {
+        Ok(match self.hash {
+            IDENTITY => self.digest == data,
+            SHA2_256 => Sha256::digest(data).as_slice() == self.digest,
+            BLAKE2B_256 => {
+                blake2b_simd::Params::new()
+                    .hash_length(32)
+                    .hash(data)
+                    .as_bytes()
+                    == self.digest
+            }
+            hash => bail!("Unsupported multihash function {hash:#x}."),
+        })
+    }
+}
+
+/// A CBOR data item, as far as CAR headers need them.
+enum Cbor<'a> {
+    Bytes(&'a [u8]),
+    Text(&'a str),
+    Array(Vec<Cbor<'a>>),
+    Map(Vec<(Cbor<'a>, Cbor<'a>)>),
+    Tag(u64, Box<Cbor<'a>>),
+    Other,
+}
+
+fn read_cbor<'a>(buf: &mut &'a [u8], depth: usize) -> Result<Cbor<'a>> {
+    if depth > MAX_DEPTH {
+        bail!("CAR header is nested too deeply.");
+    }
+    let (&initial, rest) = buf
+        .split_first()
+        .ok_or_else(|| anyhow!("Truncated CAR header."))?;
+    *buf = rest;
+    let argument = match initial & 0x1f {
+        info @ 0..=23 => u64::from(info),
+        info @ 24..=27 => read_bytes(buf, 1 << (info - 24))?
+            .iter()
+            .fold(0, |value, &byte| (value << 8) | u64::from(byte)),
+        _ => bail!("Unsupported CBOR item in CAR header."),
+    };
+    Ok(match initial >> 5 {
+        2 => Cbor::Bytes(read_bytes(buf, argument)?),
+        3 => Cbor::Text(std::str::from_utf8(read_bytes(buf, argument)?)?),
+        4 => Cbor::Array(
+            (0..argument)
+                .map(|_| read_cbor(buf, depth + 1))
+                .collect::<Result<_>>()?,
+        ),
+        5 => Cbor::Map(
+            (0..argument)
+                .map(|_| Ok((read_cbor(buf, depth + 1)?, read_cbor(buf, depth + 1)?)))
+                .collect::<Result<_>>()?,
+        ),
+        6 => Cbor::Tag(argument, Box::new(read_cbor(buf, depth + 1)?)),
+        _ => Cbor::Other,
+    })
+}
+
+/// CIDs of the roots listed in a CARv1 header.
+fn header_roots(mut header: &[u8]) -> Result<Vec<&[u8]>> {
+    let Cbor::Map(entries) = read_cbor(&mut header, 0)? else {
+        bail!("CAR header isn't a map.");
+    };
+    let roots = entries
+        .into_iter()
+        .find_map(|(key, value)| matches!(key, Cbor::Text("roots")).then_some(value));
+    let Some(Cbor::Array(roots)) = roots else {
+        bail!("CAR header lists no roots.");
+    };
+    roots
+        .into_iter()
+        .map(|root| match root {
+            // Binary CIDs behind the identity multibase prefix
+            Cbor::Tag(CID_TAG, cid) => match *cid {
+                Cbor::Bytes([0, cid @ ..]) => Ok(cid),
+                _ => bail!("Invalid root CID in CAR header."),
+            },
+            _ => bail!("Invalid root CID in CAR header."),
+        })
+        .collect()
+}
+
+/// A protobuf field value. Fixed-size fields are skipped, as neither DAG-PB
+/// nor UnixFS uses them for anything needed here.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// The fields of a protobuf message, by field number.
+fn fields(mut buf: &[u8]) -> Result<Vec<(u64, Value<'_>)>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = read_varint(&mut buf)?;
+        match key & 0x7 {
+            0 => fields.push((key >> 3, Value::Varint(read_varint(&mut buf)?))),
+            1 => {
+                read_bytes(&mut buf, 8)?;
+            }
+            2 => {
+                let length = read_varint(&mut buf)?;
+                fields.push((key >> 3, Value::Bytes(read_bytes(&mut buf, length)?)));
+            }
+            5 => {
+                read_bytes(&mut buf, 4)?;
+            }
+            wire_type => bail!("Unsupported protobuf wire type {wire_type}."),
+        }
+    }
+    Ok(fields)
+}
+
+/// A DAG-PB node: its UnixFS type and data, and its links as CID and name.
+struct Node<'a> {
+    kind: u64,
+    data: &'a [u8],
+    links: Vec<(&'a [u8], &'a str)>,
+}
+
+impl<'a> Node<'a> {
+    fn parse(block: &'a [u8]) -> Result<Self> {
+        let mut node = Self {
+            kind: UNIXFS_FILE,
+            data: &[],
+            links: Vec::new(),
+        };
+        for (field, value) in fields(block)? {
+            match (field, value) {
+                (1, Value::Bytes(unixfs)) => {
+                    for (field, value) in fields(unixfs)? {
+                        match (field, value) {
+                            (1, Value::Varint(kind)) => node.kind = kind,
+                            (2, Value::Bytes(data)) => node.data = data,
+                            _ => {}
+                        }
+                    }
+                }
+                (2, Value::Bytes(link)) => {
+                    let mut hash = None;
+                    let mut name = "";
+                    for (field, value) in fields(link)? {
+                        match (field, value) {
+                            (1, Value::Bytes(cid)) => hash = Some(cid),
+                            (2, Value::Bytes(bytes)) => name = std::str::from_utf8(bytes)?,
+                            _ => {}
+                        }
+                    }
+                    node.links
+                        .push((hash.ok_or_else(|| anyhow!("Link without a CID."))?, name));
+                }
+                _ => {}
+            }
+        }
+        Ok(node)
+    }
+}
+
+/// Blocks of a CAR file, by CID.
+struct Car<'a> {
+    blocks: HashMap<&'a [u8], Block<'a>>,
+}
+
+impl<'a> Car<'a> {
+    fn get(&self, cid: &[u8]) -> Result<&Block<'a>> {
+        self.blocks
+            .get(cid)
+            .ok_or_else(|| anyhow!("Block missing from CAR file."))
+    }
+
+    fn is_directory(&self, cid: &[u8]) -> Result<bool> {
+        let block = self.get(cid)?;
+        Ok(block.codec == DAG_PB && Node::parse(block.data)?.kind == UNIXFS_DIRECTORY)
+    }
+
+    /// Record that `cid` is being visited below `ancestors`. Identical chunks
+    /// and subdirectories share a CID, so a block may be reached more than
+    /// once, but never from within itself.
+    fn enter(cid: &'a [u8], ancestors: &mut Vec<&'a [u8]>) -> Result<()> {
+        if ancestors.contains(&cid) {
+            bail!("CAR file links a block to itself.");
+        }
+        if ancestors.len() >= MAX_DEPTH {
+            bail!("CAR file is nested more than {MAX_DEPTH} levels deep.");
+        }
+        ancestors.push(cid);
+        Ok(())
+    }
+
+    /// Concatenate the data of a file and all of its descendants, in order.
+    fn file(
+        &self,
+        cid: &'a [u8],
+        ancestors: &mut Vec<&'a [u8]>,
+        content: &mut Vec<u8>,
+    ) -> Result<()> {
+        Self::enter(cid, ancestors)?;
+        let block = self.get(cid)?;
+        match block.codec {
+            RAW => content.extend_from_slice(block.data),
+            DAG_PB => {
+                let node = Node::parse(block.data)?;
+                if !matches!(node.kind, UNIXFS_RAW | UNIXFS_FILE) {
+                    bail!("Unsupported UnixFS node type {}.", node.kind);
+                }
+                content.extend_from_slice(node.data);
+                for (link, _name) in node.links {
+                    self.file(link, ancestors, content)?;
+                }
+            }
+            codec => bail!("Unsupported block codec {codec:#x}."),
+        }
+        ancestors.pop();
+        Ok(())
+    }
+
+    fn directory(
+        &self,
+        cid: &'a [u8],
+        prefix: &str,
+        ancestors: &mut Vec<&'a [u8]>,
+        files: &mut Vec<(String, Vec<u8>)>,
+    ) -> Result<()> {
+        Self::enter(cid, ancestors)?;
+        for (link, name) in Node::parse(self.get(cid)?.data)?.links {
+            let path = format!("{prefix}{name}");
+            if self.is_directory(link)? {
+                self.directory(link, &format!("{path}/"), ancestors, files)?;
+            } else {
+                let mut content = Vec::new();
+                self.file(link, ancestors, &mut content)?;
+                files.push((path, content));
+            }
+        }
+        ancestors.pop();
+        Ok(())
+    }
+}
+
+/// Reconstruct the content of a CARv1 or CARv2 file with a single root.
+pub fn read(mut buf: &[u8]) -> Result<Root> {
+    if let Some(header) = buf.strip_prefix(CAR_V2_PRAGMA) {
+        let offset = |at: usize| -> Result<usize> {
+            let bytes = header
+                .get(at..at + 8)
+                .ok_or_else(|| anyhow!("Truncated CARv2 header."))?;
+            Ok(usize::try_from(u64::from_le_bytes(bytes.try_into()?))?)
+        };
+        let (start, size) = (offset(16)?, offset(24)?);
+        buf = buf
+            .get(start..start + size)
+            .ok_or_else(|| anyhow!("Truncated CARv2 payload."))?;
+    }
+
+    let header = read_varint(&mut buf)?;
+    let roots = header_roots(read_bytes(&mut buf, header)?)?;
+    let &[root] = roots.as_slice() else {
+        bail!("CAR file has {} roots, only one is supported.", roots.len());
+    };
+
+    let mut car = Car {
+        blocks: HashMap::new(),
+    };
+    while !buf.is_empty() {
+        let length = read_varint(&mut buf)?;
+        let mut section = read_bytes(&mut buf, length)?;
+        let cid = Cid::read(&mut section)?;
+        if !cid.matches(section)? {
+            bail!("CAR file has a block that doesn't match its CID.");
+        }
+        let block = Block {
+            codec: cid.codec,
+            data: section,
+        };
+        car.blocks.insert(cid.bytes, block);
+    }
+
+    let mut ancestors = Vec::new();
+    if car.is_directory(root)? {
+        let mut files = Vec::new();
+        car.directory(root, "", &mut ancestors, &mut files)?;
+        Ok(Root::Directory(files))
+    } else {
+        let mut content = Vec::new();
+        car.file(root, &mut ancestors, &mut content)?;
+        Ok(Root::File(content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    /// CIDv1 of a block with SHA-256.
+    fn cid(codec: u64, data: &[u8]) -> Vec<u8> {
+        let mut cid = Vec::new();
+        varint(1, &mut cid);
+        varint(codec, &mut cid);
+        varint(SHA2_256, &mut cid);
+        varint(32, &mut cid);
+        cid.extend_from_slice(&Sha256::digest(data));
+        cid
+    }
+
+    /// Length-delimited protobuf field.
+    fn field(number: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint((number << 3) | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    /// DAG-PB block of a UnixFS node.
+    fn node(kind: u64, data: &[u8], links: &[(&[u8], &str)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        for (cid, name) in links {
+            let mut link = Vec::new();
+            field(1, cid, &mut link);
+            field(2, name.as_bytes(), &mut link);
+            field(2, &link, &mut block);
+        }
+        let mut unixfs = Vec::new();
+        varint(1 << 3, &mut unixfs);
+        varint(kind, &mut unixfs);
+        field(2, data, &mut unixfs);
+        field(1, &unixfs, &mut block);
+        block
+    }
+
+    /// CARv1 file with a single root.
+    fn car(root: &[u8], blocks: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut header = vec![0xa2, 0x65];
+        header.extend_from_slice(b"roots");
+        header.extend_from_slice(&[0x81, 0xd8, 0x2a, 0x58, root.len() as u8 + 1, 0]);
+        header.extend_from_slice(root);
+        header.push(0x67);
+        header.extend_from_slice(b"version");
+        header.push(1);
+
+        let mut car = Vec::new();
+        varint(header.len() as u64, &mut car);
+        car.extend_from_slice(&header);
+        for (cid, data) in blocks {
+            varint((cid.len() + data.len()) as u64, &mut car);
+            car.extend_from_slice(cid);
+            car.extend_from_slice(data);
+        }
+        car
+    }
+
+    fn raw(data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        (cid(RAW, data), data.to_vec())
+    }
+
+    fn dag_pb(block: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+        (cid(DAG_PB, &block), block)
+    }
+
+    fn file(car: &[u8]) -> Vec<u8> {
+        match read(car).unwrap() {
+            Root::File(content) => content,
+            Root::Directory(_) => panic!("Expected a file"),
+        }
+    }
+
+    #[test]
+    fn reads_the_root_named_in_the_header() {
+        let first = raw(b"first");
+        let second = raw(b"second");
+        let car = car(&second.0, &[first, second.clone()]);
+        assert_eq!(file(&car), b"second");
+    }
+
+    #[test]
+    fn reads_files_in_link_order() {
+        let a = raw(b"a");
+        let b = raw(b"b");
+        // Identical chunks share a block
+        let root = dag_pb(node(
+            UNIXFS_FILE,
+            b"",
+            &[
+                (a.0.as_slice(), ""),
+                (b.0.as_slice(), ""),
+                (a.0.as_slice(), ""),
+            ],
+        ));
+        let car = car(&root.0, &[a, b, root.clone()]);
+        assert_eq!(file(&car), b"aba");
+    }
+
+    #[test]
+    fn reads_nested_directories() {
+        let x = raw(b"x");
+        let y = raw(b"y");
+        let sub = dag_pb(node(UNIXFS_DIRECTORY, b"", &[(y.0.as_slice(), "y.txt")]));
+        let root = dag_pb(node(
+            UNIXFS_DIRECTORY,
+            b"",
+            &[(x.0.as_slice(), "x.txt"), (sub.0.as_slice(), "sub")],
+        ));
+        let car = car(&root.0, &[x, y, sub, root.clone()]);
+        let Root::Directory(files) = read(&car).unwrap() else {
+            panic!("Expected a directory");
+        };
+        assert_eq!(
+            files,
+            [
+                ("x.txt".to_string(), b"x".to_vec()),
+                ("sub/y.txt".to_string(), b"y".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_blocks_not_matching_their_cid() {
+        let (cid, _) = raw(b"expected");
+        let car = car(&cid, &[(cid.clone(), b"tampered".to_vec())]);
+        assert!(read(&car).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_roots() {
+        let block = raw(b"content");
+        let car = car(&cid(RAW, b"elsewhere"), &[block]);
+        assert!(read(&car).is_err());
+    }
+
+    #[test]
+    fn rejects_dags_nested_too_deeply() {
+        let leaf = raw(b"leaf");
+        let mut blocks = vec![leaf.clone()];
+        let mut child = leaf.0;
+        for _ in 0..MAX_DEPTH {
+            let parent = dag_pb(node(UNIXFS_FILE, b"", &[(child.as_slice(), "")]));
+            child = parent.0.clone();
+            blocks.push(parent);
+        }
+        let car = car(&child, &blocks);
+        assert!(read(&car).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Persisted defaults for the connection and authentication options, kept in
+//! `apsisctl.toml` in the platform's configuration directory.
+
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// IP address and port to connect to
+    pub connect: Option<String>,
+
+    /// API authentication token
+    pub auth: Option<String>,
+}
+
+/// Settings that `config set` can change.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Setting {
+    Connect,
+    Auth,
+}
+
+impl Config {
+    /// Path of the configuration file, unless the platform has no
+    /// configuration directory, as without a home directory.
+    pub fn path() -> Option<PathBuf> {
+        ProjectDirs::from("tech", "throneless", "apsis")
+            .map(|dirs| dirs.config_dir().join("apsisctl.toml"))
+    }
+
+    /// Load the configuration file, if there is one.
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the configuration file, readable by its owner only as it may
+    /// hold the API token.
+    pub fn save(&self) -> Result<PathBuf> {
+        let path =
+            Self::path().ok_or_else(|| anyhow!("Failed to find the configuration directory."))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies to new files
+            if path.exists() {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        options
+            .open(&path)?
+            .write_all(toml::to_string(self)?.as_bytes())?;
+        Ok(path)
+    }
+
+    pub fn set(&mut self, setting: Setting, value: String) {
+        match setting {
+            Setting::Connect => self.connect = Some(value),
+            Setting::Auth => self.auth = Some(value),
+        }
+    }
+
+    /// The configuration as `config show` prints it, never including the API
+    /// token itself.
+    pub fn display(&self) -> String {
+        format!(
+            "connect = {}\nauth = {}",
+            self.connect.as_deref().unwrap_or("(unset)"),
+            if self.auth.is_some() {
+                "(set, redacted)"
+            } else {
+                "(unset)"
+            }
+        )
+    }
+}
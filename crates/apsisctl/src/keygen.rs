@@ -0,0 +1,82 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use anyhow::{Result, anyhow};
+use argon2::Argon2;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use clap::ValueEnum;
+use ed25519_dalek::SigningKey;
+use rand::RngCore;
+
+/// Fixed salt, so that a passphrase always derives the same key.
+const SALT: &[u8] = b"apsis convergence secret";
+
+pub type Key = [u8; 32];
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum KeyFormat {
+    Hex,
+    Base64,
+}
+
+impl KeyFormat {
+    pub fn encode(&self, key: &Key) -> String {
+        match self {
+            Self::Hex => hex::encode(key),
+            Self::Base64 => STANDARD.encode(key),
+        }
+    }
+}
+
+/// A random key from the operating system's CSPRNG.
+pub fn generate() -> Key {
+    let mut key = Key::default();
+    rand::rng().fill_bytes(&mut key);
+    key
+}
+
+/// Derive a key from a passphrase with Argon2id.
+pub fn derive(passphrase: &str) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), SALT, &mut key)
+        .map_err(|err| anyhow!("Failed to derive key: {}", err))?;
+    Ok(key)
+}
+
+/// Parse a key given as hex or base64.
+pub fn parse(key: &str) -> Result<Key> {
+    let mut decoded = Key::default();
+    if hex::decode_to_slice(key, &mut decoded).is_err() {
+        decoded = STANDARD
+            .decode(key)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow!("Key must be 32 bytes of hex or base64."))?;
+    }
+    Ok(decoded)
+}
+
+/// Parse a key given as hex or base64, returning it as the hex expected by
+/// the `X-Apsis-Convergence-Secret` header.
+pub fn to_header(key: &str) -> Result<String> {
+    Ok(hex::encode(parse(key)?))
+}
+
+/// Parse a manifest signing key given as hex or base64.
+pub fn signing_key(key: &str) -> Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&parse(key)?))
+}
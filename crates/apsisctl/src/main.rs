@@ -14,12 +14,26 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use anyhow::Result;
+mod car;
+mod config;
+mod keygen;
+mod manifest;
+mod watch;
+
+use anyhow::{Result, anyhow, bail};
+use blake2b_simd::Params;
 use clap::{Args, Parser, Subcommand};
 use clap_verbosity_flag::Verbosity;
-use std::path::PathBuf;
+use config::{Config, Setting};
+use ed25519_dalek::SigningKey;
+use keygen::KeyFormat;
+use manifest::Manifest;
+use reqwest::multipart::{Form, Part};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
+use tracing::debug;
 use tracing_log::AsTrace;
 use url::Url;
 
@@ -27,9 +41,9 @@ use url::Url;
 #[derive(Debug, Parser)] // requires `derive` feature
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// IP address and port to connect to
+    /// IP address and port to connect to (defaults to the configured one)
     #[arg(short, long)]
-    connect: String,
+    connect: Option<String>,
 
     /// Verbosity
     #[command(flatten)]
@@ -61,16 +75,73 @@ struct Output {
     /// File path
     #[arg(short, long)]
     file: Option<PathBuf>,
+
+    /// Download and discard the content, checking that it can be retrieved
+    #[arg(long)]
+    verify_only: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigAction {
+    /// Print the effective configuration, with the API token redacted
+    Show,
+
+    /// Persist a default for an option
+    #[command(arg_required_else_help = true)]
+    Set {
+        /// Option to set
+        #[arg(value_enum)]
+        setting: Setting,
+
+        /// Value to set it to
+        value: String,
+    },
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    #[command(flatten)]
+    Node(NodeCommand),
+
+    /// View or set the persisted defaults for --connect and --auth
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Generate a convergence secret for uploads, or a manifest signing key
+    Keygen {
+        /// Encoding of the printed key
+        #[arg(short, long, value_enum, default_value_t = KeyFormat::Hex)]
+        format: KeyFormat,
+
+        /// Derive the key from a passphrase instead of generating it randomly
+        #[arg(short, long)]
+        derive: Option<String>,
+
+        /// Print the public key of the generated key, for signing manifests
+        #[arg(long)]
+        signing: bool,
+    },
+}
+
+/// Commands that connect to a node.
+#[derive(Debug, Subcommand)]
+enum NodeCommand {
     /// Upload JSON or file data
     #[command(arg_required_else_help = true)]
     Upload {
-        /// API authentication token
+        /// API authentication token (defaults to the configured one)
+        #[arg(short, long)]
+        auth: Option<String>,
+
+        /// Content type of an uploaded file (guessed from its extension by default)
+        #[arg(short = 't', long)]
+        content_type: Option<String>,
+
+        /// Convergence secret as hex or base64 (a random key is used by default)
         #[arg(short, long)]
-        auth: String,
+        secret: Option<String>,
 
         /// Input selection
         #[command(flatten)]
@@ -88,6 +159,224 @@ enum Commands {
         #[arg(required = true)]
         urn: String,
     },
+
+    /// Watch a directory and upload changes, printing a new manifest URN on each change
+    #[command(arg_required_else_help = true)]
+    Watch {
+        /// API authentication token (defaults to the configured one)
+        #[arg(short, long)]
+        auth: Option<String>,
+
+        /// Milliseconds to wait for changes to settle before uploading
+        #[arg(short, long, default_value_t = 500)]
+        debounce: u64,
+
+        /// Key to sign manifests with, as hex or base64 (see `keygen --signing`)
+        #[arg(long)]
+        signing_key: Option<String>,
+
+        /// Directory to watch
+        #[arg(required = true)]
+        dir: PathBuf,
+    },
+
+    /// Import the content of an IPFS CAR file, re-encoding it with ERIS
+    #[command(arg_required_else_help = true)]
+    ImportCar {
+        /// API authentication token (defaults to the configured one)
+        #[arg(short, long)]
+        auth: Option<String>,
+
+        /// Convergence secret as hex or base64 (a random key is used by default)
+        #[arg(short, long)]
+        secret: Option<String>,
+
+        /// Key to sign the manifest of a directory with, as hex or base64 (see `keygen --signing`)
+        #[arg(long)]
+        signing_key: Option<String>,
+
+        /// CAR file
+        #[arg(required = true)]
+        file: PathBuf,
+    },
+}
+
+/// Header carrying the convergence secret of an upload.
+const CONVERGENCE_HEADER: &str = "X-Apsis-Convergence-Secret";
+
+async fn upload_json(
+    client: &reqwest::Client,
+    url: Url,
+    auth: &str,
+    data: String,
+    secret: Option<&str>,
+) -> Result<String> {
+    let mut req = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("Authorization", auth);
+    if let Some(secret) = secret {
+        req = req.header(CONVERGENCE_HEADER, secret);
+    }
+    let res = req.body(data).send().await?.error_for_status()?;
+    Ok(res.text().await?)
+}
+
+async fn upload_file(
+    client: &reqwest::Client,
+    url: Url,
+    auth: &str,
+    path: &Path,
+    content_type: Option<&str>,
+    secret: Option<&str>,
+) -> Result<String> {
+    let guessed = mime_guess::from_path(path).first_or_octet_stream();
+    let mut part = Part::stream(File::open(path).await?)
+        .mime_str(content_type.unwrap_or(guessed.essence_str()))?;
+    if let Some(name) = path.file_name() {
+        part = part.file_name(name.to_string_lossy().into_owned());
+    }
+    upload_part(client, url, auth, part, secret).await
+}
+
+async fn upload_part(
+    client: &reqwest::Client,
+    url: Url,
+    auth: &str,
+    part: Part,
+    secret: Option<&str>,
+) -> Result<String> {
+    let mut req = client.post(url).header("Authorization", auth);
+    if let Some(secret) = secret {
+        req = req.header(CONVERGENCE_HEADER, secret);
+    }
+    let res = req
+        .multipart(Form::new().part("file", part))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(res.text().await?)
+}
+
+/// Upload the content of a CAR file, returning the URN of a single file, or of
+/// a manifest mapping paths to URNs, as `watch` publishes, for a directory.
+async fn import_car(
+    client: &reqwest::Client,
+    url: Url,
+    auth: &str,
+    path: &Path,
+    secret: Option<&str>,
+    signing_key: Option<&SigningKey>,
+) -> Result<String> {
+    let bytes = tokio::fs::read(path).await?;
+    let upload = |name: String, content: Vec<u8>| {
+        let guessed = mime_guess::from_path(&name).first_or_octet_stream();
+        let part = Part::bytes(content)
+            .file_name(name)
+            .mime_str(guessed.essence_str());
+        let url = url.clone();
+        async move { upload_part(client, url, auth, part?, secret).await }
+    };
+    match car::read(&bytes)? {
+        car::Root::File(content) => {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            upload(name.into_owned(), content).await
+        }
+        car::Root::Directory(files) => {
+            let mut manifest = Manifest::new();
+            for (name, content) in files {
+                let urn = upload(name.clone(), content).await?;
+                debug!("Uploaded {} as {}", name, urn);
+                manifest.insert(name, urn);
+            }
+            upload_json(
+                client,
+                url.clone(),
+                auth,
+                manifest::to_json(&manifest, signing_key)?,
+                secret,
+            )
+            .await
+        }
+    }
+}
+
+/// Block size and root level of an ERIS read capability URN.
+fn capability_shape(urn: &str) -> Option<(usize, u8)> {
+    let encoded = urn.strip_prefix("urn:eris:")?;
+    let capability = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, encoded)?;
+    match capability.as_slice() {
+        [size @ (10 | 15), level, ..] if capability.len() == 66 => Some((1 << size, *level)),
+        _ => None,
+    }
+}
+
+/// Number of blocks ERIS encodes `bytes` of content into with blocks of
+/// `block_size`, and the level of the root of their tree.
+fn tree_shape(bytes: usize, block_size: usize) -> (usize, u8) {
+    // Padding always takes at least a byte
+    let mut width = bytes / block_size + 1;
+    let (mut blocks, mut level) = (width, 0);
+    while width > 1 {
+        width = width.div_ceil(block_size / 64);
+        blocks += width;
+        level += 1;
+    }
+    (blocks, level)
+}
+
+/// Download content and discard it, printing its size and blocks. A single
+/// block is also checked against its reference, while the server checks the
+/// blocks of a capability as it decodes them, and the size of the content is
+/// checked against the depth of the capability's tree.
+async fn verify_download(client: &reqwest::Client, url: Url, urn: &str) -> Result<()> {
+    let reference = urn
+        .strip_prefix("urn:")
+        .filter(|_| !urn.starts_with("urn:eris:"))
+        .map(|encoded| {
+            base32::decode(base32::Alphabet::Rfc4648 { padding: false }, encoded)
+                .ok_or_else(|| anyhow!("Invalid block reference."))
+        })
+        .transpose()?;
+    let mut hasher = reference
+        .is_some()
+        .then(|| Params::new().hash_length(32).to_state());
+    let mut response = client
+        .get(url)
+        .header("Accept", "application/octet-stream")
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut bytes = 0;
+    while let Some(chunk) = response.chunk().await? {
+        bytes += chunk.len();
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&chunk);
+        }
+    }
+    match (reference, hasher) {
+        (Some(reference), Some(hasher)) => {
+            if hasher.finalize().as_bytes() != reference.as_slice() {
+                bail!("Block of {bytes} bytes doesn't match its reference.");
+            }
+            println!("Verified 1 block of {bytes} bytes against its reference.");
+        }
+        _ => match capability_shape(urn) {
+            Some((block_size, level)) => {
+                let (blocks, expected) = tree_shape(bytes, block_size);
+                if expected != level {
+                    bail!(
+                        "Content of {bytes} bytes doesn't fit a tree of level {level} with blocks of {block_size} bytes."
+                    );
+                }
+                println!(
+                    "Verified {bytes} bytes in {blocks} blocks of {block_size} bytes, at tree level {level}."
+                );
+            }
+            None => println!("Retrieved {bytes} bytes."),
+        },
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -96,47 +385,200 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt()
         .with_max_level(args.verbose.log_level_filter().as_trace())
         .init();
-    let connect = args.connect;
+    // Handle the commands that work without a node
+    let command = match args.command {
+        Commands::Node(command) => command,
+        Commands::Config { action } => {
+            let mut config = Config::load()?;
+            match action {
+                ConfigAction::Show => {
+                    config.connect = args.connect.or(config.connect);
+                    println!("{}", config.display());
+                }
+                ConfigAction::Set { setting, value } => {
+                    config.set(setting, value);
+                    println!("Wrote to file {}.", config.save()?.to_string_lossy());
+                }
+            }
+            return Ok(());
+        }
+        Commands::Keygen {
+            format,
+            derive,
+            signing,
+        } => {
+            let key = match derive {
+                Some(passphrase) => {
+                    eprintln!(
+                        "Warning: a passphrase-derived key is only as strong as the passphrase. \
+                         Anyone who guesses it can derive the key and confirm whether given \
+                         content was uploaded with it."
+                    );
+                    keygen::derive(&passphrase)?
+                }
+                None => keygen::generate(),
+            };
+            println!("{}", format.encode(&key));
+            if signing {
+                let public = SigningKey::from_bytes(&key).verifying_key();
+                eprintln!("Public key: {}", hex::encode(public.as_bytes()));
+            }
+            return Ok(());
+        }
+    };
+    let config = Config::load()?;
+    let connect = args.connect.or(config.connect).ok_or_else(|| {
+        anyhow!(
+            "--connect is required for this command, or set with `apsisctl config set connect`."
+        )
+    })?;
+    let resolve_auth = |auth: Option<String>| {
+        auth.or_else(|| config.auth.clone()).ok_or_else(|| {
+            anyhow!("--auth is required for this command, or set with `apsisctl config set auth`.")
+        })
+    };
 
     let mut url = Url::parse(&connect).expect("Invalid connection URI.");
     url = url.join("uri-res/")?;
     let client = reqwest::Client::new();
-    match args.command {
-        Commands::Upload { auth, input } => {
+    match command {
+        NodeCommand::Upload {
+            auth,
+            content_type,
+            secret,
+            input,
+        } => {
             let url = url.join("R2N")?;
+            let auth = resolve_auth(auth)?;
+            let secret = secret.as_deref().map(keygen::to_header).transpose()?;
             if let Some(data) = input.json {
-                let res = client
-                    .post(url)
-                    .header("Content-Type", "application/json")
-                    .header("Authorization", auth)
-                    .body(data)
-                    .send()
-                    .await?;
-                println!("{}", res.text().await?);
+                println!(
+                    "{}",
+                    upload_json(&client, url, &auth, data, secret.as_deref()).await?
+                );
             } else if let Some(path) = input.file {
-                let file = File::open(path).await?;
-                let res = client
-                    .post(url)
-                    .header("Authorization", auth)
-                    .body(file)
-                    .send()
-                    .await?;
-                println!("{}", res.text().await?);
+                println!(
+                    "{}",
+                    upload_file(
+                        &client,
+                        url,
+                        &auth,
+                        &path,
+                        content_type.as_deref(),
+                        secret.as_deref()
+                    )
+                    .await?
+                );
             }
         }
-        Commands::Download { output, urn } => {
+        NodeCommand::Download { output, urn } => {
             let route = "N2R?".to_owned() + &urn;
             let url = url.join(&route)?;
-            if output.stdout {
-                println!("{}", client.get(url).send().await?.text().await?);
+            if output.verify_only {
+                verify_download(&client, url, &urn).await?;
+            } else if output.stdout {
+                // Content that isn't JSON is still printed, as bytes
+                let res = client
+                    .get(url)
+                    .header("Accept", "application/json, application/octet-stream")
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                println!("{}", res.text().await?);
             } else if let Some(path) = output.file {
+                let res = client
+                    .get(url)
+                    .header("Accept", "application/octet-stream")
+                    .send()
+                    .await?
+                    .error_for_status()?;
                 let mut file = File::create(&path).await?;
-                file.write_all(&client.get(url).send().await?.bytes().await?)
-                    .await?;
+                file.write_all(&res.bytes().await?).await?;
                 file.flush().await?;
                 println!("Wrote to file {}.", path.to_string_lossy());
             }
         }
+        NodeCommand::Watch {
+            auth,
+            debounce,
+            signing_key,
+            dir,
+        } => {
+            let url = url.join("R2N")?;
+            let auth = resolve_auth(auth)?;
+            let signing_key = signing_key
+                .as_deref()
+                .map(keygen::signing_key)
+                .transpose()?;
+            watch::watch(
+                &client,
+                url,
+                &auth,
+                &dir,
+                Duration::from_millis(debounce),
+                signing_key.as_ref(),
+            )
+            .await?;
+        }
+        NodeCommand::ImportCar {
+            auth,
+            secret,
+            signing_key,
+            file,
+        } => {
+            let url = url.join("R2N")?;
+            let auth = resolve_auth(auth)?;
+            let secret = secret.as_deref().map(keygen::to_header).transpose()?;
+            let signing_key = signing_key
+                .as_deref()
+                .map(keygen::signing_key)
+                .transpose()?;
+            println!(
+                "{}",
+                import_car(
+                    &client,
+                    url,
+                    &auth,
+                    &file,
+                    secret.as_deref(),
+                    signing_key.as_ref()
+                )
+                .await?
+            );
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_shape_counts_padding_and_nodes() {
+        assert_eq!(tree_shape(0, 1024), (1, 0));
+        assert_eq!(tree_shape(1023, 1024), (1, 0));
+        // A full block is followed by a block of padding
+        assert_eq!(tree_shape(1024, 1024), (3, 1));
+        // 16 references fit in a node of 1KiB
+        assert_eq!(tree_shape(15 * 1024, 1024), (17, 1));
+        assert_eq!(tree_shape(16 * 1024, 1024), (17 + 2 + 1, 2));
+        assert_eq!(tree_shape(32 * 1024, 32 * 1024), (3, 1));
+    }
+
+    #[test]
+    fn capability_shape_reads_block_size_and_level() {
+        let mut capability = vec![0; 66];
+        capability[..2].copy_from_slice(&[10, 2]);
+        let urn = |capability: &[u8]| {
+            "urn:eris:".to_owned()
+                + &base32::encode(base32::Alphabet::Rfc4648 { padding: false }, capability)
+        };
+        assert_eq!(capability_shape(&urn(&capability)), Some((1024, 2)));
+        capability[0] = 15;
+        assert_eq!(capability_shape(&urn(&capability)), Some((32 * 1024, 2)));
+        capability[0] = 11;
+        assert_eq!(capability_shape(&urn(&capability)), None);
+        assert_eq!(capability_shape(&urn(&capability[..65])), None);
+    }
+}
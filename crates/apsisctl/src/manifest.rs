@@ -0,0 +1,39 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Directory manifests, optionally signed so that a gateway pinning the
+//! publisher's key can tell they haven't been tampered with.
+
+use anyhow::Result;
+use ed25519_dalek::{Signer, SigningKey};
+use std::collections::BTreeMap;
+
+/// Mapping of paths relative to the published directory to capability URNs.
+pub type Manifest = BTreeMap<String, String>;
+
+/// Serialize a manifest, wrapping its entries with a signature over their
+/// compact, key-sorted JSON when a signing key is given.
+pub fn to_json(manifest: &Manifest, key: Option<&SigningKey>) -> Result<String> {
+    let Some(key) = key else {
+        return Ok(serde_json::to_string(manifest)?);
+    };
+    let signature = key.sign(&serde_json::to_vec(manifest)?);
+    Ok(serde_json::json!({
+        "entries": manifest,
+        "signature": hex::encode(signature.to_bytes()),
+    })
+    .to_string())
+}
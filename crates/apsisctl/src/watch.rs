@@ -0,0 +1,161 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use anyhow::Result;
+use ed25519_dalek::SigningKey;
+use notify::{Event, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::manifest::{self, Manifest};
+use crate::{upload_file, upload_json};
+
+/// Editor swap, backup and lock files that should never be published.
+fn is_ignored(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return true;
+    };
+    name.starts_with(".#")
+        || name.ends_with('~')
+        || name.ends_with(".swp")
+        || name.ends_with(".swx")
+        || name.ends_with(".tmp")
+        || name == "4913"
+}
+
+fn relative(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<_> = relative
+        .components()
+        .map(|part| part.as_os_str().to_str())
+        .collect::<Option<_>>()?;
+    Some(parts.join("/"))
+}
+
+fn scan(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            scan(&path, files)?;
+        } else if path.is_file() && !is_ignored(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Bring the manifest entries under `path` in line with what is on disk.
+async fn sync_path(
+    client: &reqwest::Client,
+    url: &Url,
+    auth: &str,
+    root: &Path,
+    path: &Path,
+    manifest: &mut Manifest,
+) -> Result<bool> {
+    let Some(key) = relative(root, path) else {
+        return Ok(false);
+    };
+    if path.is_dir() {
+        let mut files = Vec::new();
+        scan(path, &mut files)?;
+        let mut changed = false;
+        for file in files {
+            changed |= Box::pin(sync_path(client, url, auth, root, &file, manifest)).await?;
+        }
+        return Ok(changed);
+    }
+    if path.is_file() && !is_ignored(path) {
+        let urn = upload_file(client, url.clone(), auth, path, None, None).await?;
+        debug!("Uploaded {} as {}", key, urn);
+        return Ok(manifest.insert(key, urn.clone()) != Some(urn));
+    }
+
+    // The path is gone: drop it, and anything beneath it if it was a directory
+    let prefix = format!("{}/", key);
+    let before = manifest.len();
+    manifest.retain(|entry, _| entry != &key && !entry.starts_with(&prefix));
+    Ok(manifest.len() != before)
+}
+
+async fn publish(
+    client: &reqwest::Client,
+    url: &Url,
+    auth: &str,
+    manifest: &Manifest,
+    signing_key: Option<&SigningKey>,
+) -> Result<()> {
+    let urn = upload_json(
+        client,
+        url.clone(),
+        auth,
+        manifest::to_json(manifest, signing_key)?,
+        None,
+    )
+    .await?;
+    println!("{}", urn);
+    Ok(())
+}
+
+/// Upload everything under `dir`, then keep the uploads and the manifest in
+/// sync with the directory, printing a new manifest URN after every change.
+pub async fn watch(
+    client: &reqwest::Client,
+    url: Url,
+    auth: &str,
+    dir: &Path,
+    debounce: Duration,
+    signing_key: Option<&SigningKey>,
+) -> Result<()> {
+    let root = tokio::fs::canonicalize(dir).await?;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+        Ok(event) => {
+            let _ = tx.send(event.paths);
+        }
+        Err(err) => warn!("Watch error: {}", err),
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    let mut manifest = Manifest::new();
+    sync_path(client, &url, auth, &root, &root, &mut manifest).await?;
+    publish(client, &url, auth, &manifest, signing_key).await?;
+
+    while let Some(paths) = rx.recv().await {
+        // Collect events until the directory has been quiet for `debounce`,
+        // so that write-then-rename saves settle before anything is read
+        let mut pending: HashSet<PathBuf> = paths.into_iter().collect();
+        while let Ok(Some(paths)) = tokio::time::timeout(debounce, rx.recv()).await {
+            pending.extend(paths);
+        }
+
+        let mut changed = false;
+        for path in pending {
+            match sync_path(client, &url, auth, &root, &path, &mut manifest).await {
+                Ok(updated) => changed |= updated,
+                Err(err) => warn!("Failed to sync {}: {}", path.to_string_lossy(), err),
+            }
+        }
+        if changed {
+            publish(client, &url, auth, &manifest, signing_key).await?;
+        }
+    }
+    Ok(())
+}
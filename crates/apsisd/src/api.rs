@@ -16,41 +16,159 @@
 
 use axum::{
     RequestExt,
-    body::Bytes,
+    body::{Body, Bytes, HttpBody},
     debug_handler,
-    extract::{FromRequest, Json, Multipart, Request, State},
+    extract::{
+        FromRequest, FromRequestParts, Json, MatchedPath, Multipart, Path, Query, RawQuery,
+        Request, State,
+    },
     http::{
-        HeaderMap, StatusCode,
-        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+        header::{
+            ACCEPT, ACCEPT_RANGES, ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE,
+            CONTENT_TYPE, ETAG, IF_MATCH, LAST_MODIFIED, RANGE, RETRY_AFTER,
+        },
+        request::Parts,
     },
     response::{IntoResponse, Response},
 };
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
+use clap::ValueEnum;
+use ed25519_dalek::VerifyingKey;
 use eris_rs::{
     decode::decode,
-    encode::encode,
-    types::{BlockSize, BlockStorageError, BlockWithReference, ReadCapability, Reference},
+    types::{BlockSize, BlockStorageError, ReadCapability, Reference},
 };
-use mainline::Dht;
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
-use serde_json::Value;
-use std::io;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+use tokio::runtime::Handle;
+use tokio::sync::{Semaphore, mpsc};
 use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::io::StreamReader;
 use tokio_util::task::TaskTracker;
+use tracing::warn;
+
+use crate::chain::Link;
+use crate::conditional;
+use crate::db::{Db, GcPolicy};
+use crate::dht::SharedDht;
+use crate::drain::Drain;
+use crate::error::{ApsisError, ApsisErrorKind};
+use crate::manifest;
+use crate::metrics::Failure;
+use crate::prefetch::Prefetch;
+use crate::ratelimit::RateLimit;
+use crate::tree::{self, Key};
+use crate::tree::{Layout, Tree};
+use crate::upload::{self, Encoded, InFlight, Retention};
+use crate::utils::{self, HashAlgorithm, RefEncoding};
+use crate::webhook::Webhooks;
+
+/// Prefix of the current, stable API. The same routes are also served
+/// without a prefix for compatibility with existing clients.
+pub const API_PREFIX: &str = "/v1";
+
+/// Decoded chunks buffered ahead of a streaming download.
+const STREAM_BUFFER_CHUNKS: usize = 16;
+
+/// Upload header selecting how long blocks are kept locally.
+const RETENTION_HEADER: &str = "X-Apsis-Retention";
+
+/// Upload header overriding the block size, in bytes, chosen for the content.
+const BLOCK_SIZE_HEADER: &str = "X-Apsis-Block-Size";
+
+/// Response header set when served content is a manifest signed with the
+/// configured manifest key.
+const MANIFEST_VERIFIED_HEADER: &str = "X-Apsis-Manifest-Verified";
+
+/// Upload header requesting that the upload is decoded and compared to the
+/// original before succeeding.
+const VERIFY_HEADER: &str = "X-Apsis-Verify-Roundtrip";
 
-use crate::db::Db;
-use crate::utils;
+/// Upload header carrying a convergence secret.
+const CONVERGENCE_HEADER: &str = "X-Apsis-Convergence-Secret";
+
+/// Seconds clients are asked to wait before retrying an overloaded upload.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// Lines of a streamed batch upload encoded at once.
+const BATCH_CONCURRENCY: usize = 8;
+
+/// Longest line of a streamed batch upload, matching the default limit on
+/// JSON request bodies.
+const MAX_BATCH_LINE_BYTES: usize = 2 * 1024 * 1024;
+
+/// Links a log read follows at most.
+const MAX_LOG_LINKS: usize = 4096;
+
+/// Bytes of chunks a log read returns at most.
+const MAX_LOG_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Largest content taken for a link record, which only holds two URNs.
+const MAX_LINK_BYTES: u64 = 1024;
+
+/// Largest content parsed as JSON to serve it, so that a read doesn't hold
+/// more than that in memory. Larger content is only served as bytes.
+const MAX_JSON_BYTES: u64 = 16 * 1024 * 1024;
 
 #[derive(Clone)]
 pub struct ApiState {
+    pub admin_auth: Option<String>,
+    pub admin_limit: RateLimit,
+    pub announce_permits: Arc<Semaphore>,
     pub auth: String,
-    pub dht: Arc<Dht>,
+    pub default_accept: Option<DefaultAccept>,
+    pub defer_announce: bool,
+    pub dht: SharedDht,
+    pub drain: Drain,
+    pub ephemeral_ttl: u64,
+    pub fetch_priority: FetchPriority,
+    pub gc_policy: GcPolicy,
+    pub in_flight: InFlight,
+    pub inline_cache_bytes: u64,
+    pub inline_max_bytes: Option<u64>,
+    pub manifest_key: Option<VerifyingKey>,
+    pub max_blocks: usize,
+    pub max_pending_compaction_bytes: u64,
+    pub pin_on_fetch: bool,
+    /// Port announced on the DHT, if blocks are announced at all.
     pub port: Option<u16>,
-    pub rng: ChaCha20Rng,
+    pub prefetch_blocks: usize,
+    pub read_timeout: Option<Duration>,
+    /// Shared so that every clone of the state draws from one stream.
+    pub rng: Arc<Mutex<ChaCha20Rng>>,
+    pub root_capability: Option<String>,
     pub store: Db,
+    pub strict_capabilities: bool,
     pub tracker: TaskTracker,
+    pub upload_buffer: usize,
+    pub verify_local_reads: bool,
+    pub webhooks: Webhooks,
+    pub write_queue: usize,
+    pub write_workers: usize,
+}
+
+impl ApiState {
+    /// When a read starting now has to be done fetching blocks by.
+    fn read_deadline(&self) -> Option<Instant> {
+        self.read_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Whether content of `length` bytes is checked as a signed manifest,
+    /// which takes decoding it in full. Manifests are JSON, so only content
+    /// small enough to be parsed is checked.
+    fn checks_manifest(&self, length: u64) -> bool {
+        self.manifest_key.is_some() && length <= MAX_JSON_BYTES
+    }
 }
 
 pub enum Content {
@@ -85,22 +203,24 @@ where
                     .map_err(|err| err.into_response())?;
                 Ok(Self::File(body))
             }
-            _ => Err((StatusCode::UNSUPPORTED_MEDIA_TYPE).into_response()),
+            _ => {
+                Failure::UnsupportedMedia.record();
+                Err((StatusCode::UNSUPPORTED_MEDIA_TYPE).into_response())
+            }
         }
     }
 }
 
 pub struct DynamicQuery(String);
 
-impl<S> FromRequest<S> for DynamicQuery
+impl<S> FromRequestParts<S> for DynamicQuery
 where
-    Bytes: FromRequest<S>,
     S: Send + Sync,
 {
     type Rejection = Response;
 
-    async fn from_request(req: Request, _state: &S) -> Result<Self, Self::Rejection> {
-        if let Some(query) = req.uri().query() {
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(query) = parts.uri.query() {
             Ok(Self(query.to_owned()))
         } else {
             Err(StatusCode::NOT_FOUND.into_response())
@@ -108,150 +228,2404 @@ where
     }
 }
 
+/// Fallback representation for downloads whose `Accept` header names no
+/// supported type.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DefaultAccept {
+    OctetStream,
+    Json,
+}
+
+/// Sources consulted for blocks when reading, in order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum FetchPriority {
+    /// The local store, then the DHT.
+    #[default]
+    LocalFirst,
+    /// The DHT, then the local store.
+    DhtFirst,
+    /// Only the local store.
+    LocalOnly,
+    /// Only the DHT.
+    DhtOnly,
+}
+
+/// Representation negotiated for a download.
+enum Representation {
+    Bytes,
+    /// JSON, and whether the client also accepts the raw bytes should the
+    /// content not parse as JSON.
+    Json {
+        fallback: bool,
+    },
+    Unsupported(HeaderValue),
+}
+
+/// Media ranges of an `Accept` header in order of preference, dropping
+/// parameters and anything with `q=0`.
+fn media_ranges(accept: &str) -> Vec<String> {
+    let mut ranges: Vec<(String, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let media = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!media.is_empty() && quality > 0.0).then_some((media, quality))
+        })
+        .collect();
+    // Stable, so equally preferred ranges keep their order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges.into_iter().map(|(media, _)| media).collect()
+}
+
+fn accepts_bytes(media: &str) -> bool {
+    matches!(media, "application/octet-stream" | "application/*" | "*/*")
+}
+
+impl Representation {
+    fn negotiate(headers: &HeaderMap, default_accept: Option<DefaultAccept>) -> Self {
+        let Some(accept) = headers.get(ACCEPT) else {
+            return Self::Bytes;
+        };
+        let ranges = accept.to_str().map(media_ranges).unwrap_or_default();
+        for (index, media) in ranges.iter().enumerate() {
+            if media == "application/json" {
+                let fallback = ranges[index + 1..].iter().any(|media| accepts_bytes(media));
+                return Self::Json { fallback };
+            }
+            if accepts_bytes(media) {
+                return Self::Bytes;
+            }
+        }
+        match default_accept {
+            Some(DefaultAccept::OctetStream) => Self::Bytes,
+            Some(DefaultAccept::Json) => Self::Json { fallback: false },
+            None => Self::Unsupported(accept.clone()),
+        }
+    }
+}
+
+/// A single byte range from a `Range: bytes=...` header.
+#[derive(Clone, Copy, Debug)]
+enum ByteRange {
+    FromTo(u64, u64),
+    From(u64),
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parse the `Range` header. Multiple ranges are not supported and are
+    /// treated as if no range had been requested.
+    fn parse(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(RANGE)?.to_str().ok()?;
+        let spec = value.strip_prefix("bytes=")?.trim();
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_once('-')?;
+        match (start.trim(), end.trim()) {
+            ("", suffix) => suffix.parse().ok().map(Self::Suffix),
+            (start, "") => start.parse().ok().map(Self::From),
+            (start, end) => Some(Self::FromTo(start.parse().ok()?, end.parse().ok()?)),
+        }
+    }
+
+    /// Resolve against the content length into inclusive bounds.
+    fn resolve(&self, length: u64) -> Option<(u64, u64)> {
+        match *self {
+            Self::FromTo(start, end) if start <= end && start < length => {
+                Some((start, end.min(length - 1)))
+            }
+            Self::From(start) if start < length => Some((start, length - 1)),
+            Self::Suffix(suffix) if suffix > 0 && length > 0 => {
+                Some((length.saturating_sub(suffix), length - 1))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn partial_content(body: Bytes, start: u64, end: u64, length: u64) -> Response {
+    (
+        StatusCode::PARTIAL_CONTENT,
+        [
+            (ACCEPT_RANGES, "bytes".to_owned()),
+            (CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, length)),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+fn range_not_satisfiable(length: u64) -> Response {
+    (
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        [
+            (ACCEPT_RANGES, "bytes".to_owned()),
+            (CONTENT_RANGE, format!("bytes */{}", length)),
+        ],
+    )
+        .into_response()
+}
+
+/// Serve a byte range by decoding only the leaves covering it.
+fn read_partial<F>(
+    capability: &ReadCapability,
+    layout: &Layout,
+    range: ByteRange,
+    read_block: &F,
+) -> io::Result<Response>
+where
+    F: Fn(Reference) -> Result<Vec<u8>, BlockStorageError>,
+{
+    let tree = Tree::new(capability, read_block);
+    let length = layout.content_length();
+    let Some((start, end)) = range.resolve(length) else {
+        return Ok(range_not_satisfiable(length));
+    };
+    let mut buf = Vec::with_capacity((end - start + 1) as usize);
+    tree.read_range(layout, start, end, &mut buf)?;
+    Ok(partial_content(buf.into(), start, end, length))
+}
+
+/// Check decoded content against the manifest key, if one is configured,
+/// whichever representation it is served in. Returns whether it is a signed
+/// manifest whose signature checks out, or the response refusing one whose
+/// signature doesn't.
+fn verify_manifest(buf: &[u8], manifest_key: Option<&VerifyingKey>) -> Result<bool, Response> {
+    let Some(key) = manifest_key.filter(|_| buf.len() as u64 <= MAX_JSON_BYTES) else {
+        return Ok(false);
+    };
+    let Ok(json) = serde_json::from_slice::<Value>(buf) else {
+        return Ok(false);
+    };
+    match manifest::verify(&json, key) {
+        Some(true) => Ok(true),
+        Some(false) => {
+            Failure::BadSignature.record();
+            Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Manifest signature doesn't match the configured manifest key.".to_owned(),
+            )
+                .into_response())
+        }
+        None => Ok(false),
+    }
+}
+
+/// Serve decoded content as JSON if it parses as such, otherwise as bytes
+/// if the client accepts them.
+fn json_content(buf: Bytes, fallback: bool) -> Response {
+    if let Ok(json) = serde_json::from_slice::<Value>(&buf) {
+        Json(json).into_response()
+    } else if fallback {
+        full_content(buf, None)
+    } else {
+        Failure::NotJson.record();
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Stored content is not JSON; request application/octet-stream to download it as bytes."
+                .to_owned(),
+        )
+            .into_response()
+    }
+}
+
+fn json_too_large() -> Response {
+    Failure::NotJson.record();
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Stored content is too large to serve as JSON; request application/octet-stream to download it as bytes."
+            .to_owned(),
+    )
+        .into_response()
+}
+
+/// Serve fully decoded content, slicing it if a range was requested.
+fn full_content(buf: Bytes, range: Option<ByteRange>) -> Response {
+    let length = buf.len() as u64;
+    match range.map(|range| range.resolve(length)) {
+        Some(Some((start, end))) => {
+            partial_content(buf.slice(start as usize..=end as usize), start, end, length)
+        }
+        Some(None) => range_not_satisfiable(length),
+        None => ([(ACCEPT_RANGES, "bytes")], buf).into_response(),
+    }
+}
+
+/// Map a failed upload to a status and message, recording its cause.
+fn encode_failure(err: ApsisError, message: &str) -> (StatusCode, String) {
+    match err.inner() {
+        ApsisErrorKind::TooManyBlocks(_) => {
+            Failure::TooManyBlocks.record();
+            (StatusCode::PAYLOAD_TOO_LARGE, err.to_string())
+        }
+        ApsisErrorKind::Verify(_) => {
+            Failure::VerifyFailure.record();
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        }
+        _ => {
+            Failure::EncodeFailure.record();
+            (StatusCode::UNPROCESSABLE_ENTITY, message.to_owned())
+        }
+    }
+}
+
+/// Writer forwarding decoded content to a streaming response body.
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_err| io::Error::new(io::ErrorKind::BrokenPipe, "Client went away."))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Blocking reader over chunks sent by an async producer, such as a multipart
+/// field, so the producer waits whenever the channel is full.
+struct ChannelReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len());
+        buf[..read].copy_from_slice(&self.chunk.split_to(read));
+        Ok(read)
+    }
+}
+
+/// Stream decoded content as it is decoded, rather than buffering all of it.
+/// `length` comes from the tree's layout, so it is known before any content.
+fn stream_content<F>(capability: ReadCapability, read_block: F, length: u64) -> Response
+where
+    F: Fn(Reference) -> Result<Vec<u8>, BlockStorageError> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+    task::spawn_blocking(move || {
+        let mut writer = ChannelWriter(tx);
+        if decode(capability, &mut writer, &read_block).is_err() {
+            Failure::DecodeFailure.record();
+            let _ = writer
+                .0
+                .blocking_send(Err(io::Error::other("Failed to dereference capability.")));
+        }
+    });
+    (
+        [
+            (CONTENT_LENGTH, length.to_string()),
+            (CONTENT_TYPE, "application/octet-stream".to_owned()),
+            (ACCEPT_RANGES, "bytes".to_owned()),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+/// Response to a method a route doesn't explicitly support, including
+/// `TRACE` and `CONNECT`. The `Allow` header listing the supported methods is
+/// added by the router.
+pub async fn method_not_allowed() -> impl IntoResponse {
+    (StatusCode::METHOD_NOT_ALLOWED, "Method not allowed.")
+}
+
+/// Limits and options of an upload route.
+#[derive(Debug, Serialize)]
+struct UploadInfo {
+    max_blocks: usize,
+    block_sizes: [usize; 2],
+    ephemeral_ttl: u64,
+    headers: [&'static str; 4],
+}
+
+/// A route's self-description, returned for `OPTIONS`.
+#[derive(Debug, Serialize)]
+struct RouteInfo {
+    methods: &'static [&'static str],
+    authenticated: bool,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    accepts: &'static [&'static str],
+    produces: &'static [&'static str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload: Option<UploadInfo>,
+}
+
+/// Describe the matched route, listing its methods in the `Allow` header and
+/// what it accepts, produces and limits uploads to in the body.
+#[debug_handler]
+pub async fn describe(State(state): State<ApiState>, path: MatchedPath) -> Response {
+    let path = path.as_str();
+    let path = path
+        .strip_prefix(API_PREFIX)
+        .filter(|path| path.starts_with('/'))
+        .unwrap_or(path);
+    let upload = || {
+        Some(UploadInfo {
+            max_blocks: state.max_blocks,
+            block_sizes: [1024, 32768],
+            ephemeral_ttl: state.ephemeral_ttl,
+            headers: [
+                BLOCK_SIZE_HEADER,
+                CONVERGENCE_HEADER,
+                RETENTION_HEADER,
+                VERIFY_HEADER,
+            ],
+        })
+    };
+    let info = match path {
+        "/" | "/uri-res/N2R" => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: false,
+            accepts: &[],
+            produces: &["application/octet-stream", "application/json"],
+            upload: None,
+        },
+        "/content/refs" => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: false,
+            accepts: &[],
+            produces: &["application/json", "application/x-ndjson"],
+            upload: None,
+        },
+        "/content/log" => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: false,
+            accepts: &[],
+            produces: &["application/octet-stream"],
+            upload: None,
+        },
+        "/uri-res/R2N" => RouteInfo {
+            methods: &["POST"],
+            authenticated: true,
+            accepts: &["application/json", "multipart/form-data"],
+            produces: &["text/plain"],
+            upload: upload(),
+        },
+        "/content/patch" => RouteInfo {
+            methods: &["POST"],
+            authenticated: true,
+            accepts: &["application/json"],
+            produces: &["text/plain"],
+            upload: upload(),
+        },
+        "/content/batch-stream" => RouteInfo {
+            methods: &["POST"],
+            authenticated: true,
+            accepts: &["application/x-ndjson"],
+            produces: &["application/x-ndjson"],
+            upload: upload(),
+        },
+        "/content/append" => RouteInfo {
+            methods: &["POST"],
+            authenticated: true,
+            accepts: &["*/*"],
+            produces: &["text/plain"],
+            upload: upload(),
+        },
+        "/admin/block/{reference}" => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: true,
+            accepts: &[],
+            produces: &["application/octet-stream"],
+            upload: None,
+        },
+        "/admin/block/{reference}/capabilities" => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: true,
+            accepts: &[],
+            produces: &["application/json"],
+            upload: None,
+        },
+        "/admin/gc" => RouteInfo {
+            methods: &["POST"],
+            authenticated: true,
+            accepts: &[],
+            produces: &["application/json"],
+            upload: None,
+        },
+        "/readyz" => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: false,
+            accepts: &[],
+            produces: &["text/plain"],
+            upload: None,
+        },
+        "/drain-status" => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: false,
+            accepts: &[],
+            produces: &["application/json"],
+            upload: None,
+        },
+        "/version" => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: false,
+            accepts: &[],
+            produces: &["application/json"],
+            upload: None,
+        },
+        // Debugging endpoints
+        _ => RouteInfo {
+            methods: &["GET", "HEAD"],
+            authenticated: true,
+            accepts: &[],
+            produces: &["application/json"],
+            upload: None,
+        },
+    };
+    let allow = [info.methods, &["OPTIONS"]].concat().join(", ");
+    ([(ALLOW, allow)], Json(info)).into_response()
+}
+
+#[debug_handler]
+pub async fn version() -> impl IntoResponse {
+    Json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "api": API_PREFIX.trim_start_matches('/'),
+    }))
+}
+
+/// Ready to serve unless draining for shutdown, so that load balancers stop
+/// routing here while in-flight work completes.
+#[debug_handler]
+pub async fn readyz(State(state): State<ApiState>) -> Response {
+    if state.drain.is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "Draining.".to_owned()).into_response()
+    } else {
+        "Ready.".into_response()
+    }
+}
+
+/// Progress of a graceful shutdown.
+#[derive(Debug, Serialize)]
+struct DrainStatus {
+    draining: bool,
+    requests: usize,
+    tasks: usize,
+}
+
+/// Report whether the server is draining, and how many requests and
+/// background tasks, such as announcements and webhooks, are in flight.
+#[debug_handler]
+pub async fn drain_status(State(state): State<ApiState>) -> Response {
+    Json(DrainStatus {
+        draining: state.drain.is_draining(),
+        requests: state.drain.requests(),
+        tasks: state.tracker.len(),
+    })
+    .into_response()
+}
+
+/// Retention requested through the `X-Apsis-Retention` header, either
+/// `persistent` (the default) or `ephemeral`.
+fn retention(headers: &HeaderMap, ephemeral_ttl: u64) -> Option<Retention> {
+    match headers.get(RETENTION_HEADER).map(|value| value.to_str()) {
+        None => Some(Retention::Persistent),
+        Some(Ok(value)) if value.eq_ignore_ascii_case("persistent") => Some(Retention::Persistent),
+        Some(Ok(value)) if value.eq_ignore_ascii_case("ephemeral") => Some(Retention::Ephemeral {
+            expires_at: utils::unix_time().saturating_add(ephemeral_ttl),
+        }),
+        Some(_) => None,
+    }
+}
+
+/// Convergence secret supplied through the `X-Apsis-Convergence-Secret`
+/// header as 64 hex digits. Without one, a random key is used per upload.
+fn convergence_secret(headers: &HeaderMap) -> Result<Option<Key>, Response> {
+    let Some(value) = headers.get(CONVERGENCE_HEADER) else {
+        return Ok(None);
+    };
+    let mut secret = Key::default();
+    match value
+        .to_str()
+        .ok()
+        .and_then(|value| hex::decode_to_slice(value, &mut secret).ok())
+    {
+        Some(()) => Ok(Some(secret)),
+        None => {
+            Failure::InvalidUpload.record();
+            Err((
+                StatusCode::BAD_REQUEST,
+                format!("{CONVERGENCE_HEADER} must be 32 hex-encoded bytes."),
+            )
+                .into_response())
+        }
+    }
+}
+
+/// A random content key, drawn from the shared RNG.
+fn random_key(state: &ApiState) -> Key {
+    let mut key = [0u8; 32];
+    state
+        .rng
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .fill_bytes(&mut key);
+    key
+}
+
+/// Options shared by every upload handler.
+struct Upload {
+    options: upload::Options,
+    /// Block size requested by the client, if any.
+    block_size: Option<BlockSize>,
+}
+
+impl Upload {
+    /// Read the upload options from the request headers, drawing a random key
+    /// unless a convergence secret is given. Fails with the response to send
+    /// if the headers are invalid or the store is overloaded.
+    fn from_headers(state: &ApiState, headers: &HeaderMap) -> Result<Self, Response> {
+        let Some(retention) = retention(headers, state.ephemeral_ttl) else {
+            Failure::InvalidUpload.record();
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{RETENTION_HEADER} must be `persistent` or `ephemeral`."),
+            )
+                .into_response());
+        };
+
+        // Ephemeral blocks are only kept for announcing, which takes a port
+        if retention != Retention::Persistent && state.port.is_none() {
+            Failure::InvalidUpload.record();
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Ephemeral uploads need a port to announce blocks on.",
+            )
+                .into_response());
+        }
+
+        let secret = convergence_secret(headers)?;
+
+        let block_size = match headers.get(BLOCK_SIZE_HEADER).map(|value| value.to_str()) {
+            None => None,
+            Some(Ok("1024")) => Some(BlockSize::Size1KiB),
+            Some(Ok("32768")) => Some(BlockSize::Size32KiB),
+            Some(_) => {
+                Failure::InvalidUpload.record();
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("{BLOCK_SIZE_HEADER} must be `1024` or `32768`."),
+                )
+                    .into_response());
+            }
+        };
+
+        let verify = match headers.get(VERIFY_HEADER).map(|value| value.to_str()) {
+            None => false,
+            Some(Ok(value)) if value.eq_ignore_ascii_case("true") => true,
+            Some(Ok(value)) if value.eq_ignore_ascii_case("false") => false,
+            Some(_) => {
+                Failure::InvalidUpload.record();
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("{VERIFY_HEADER} must be `true` or `false`."),
+                )
+                    .into_response());
+            }
+        };
+
+        // Ask clients to back off rather than queue behind stalled writes
+        if state
+            .store
+            .is_overloaded(state.max_pending_compaction_bytes)
+            .unwrap_or(false)
+        {
+            Failure::Overloaded.record();
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+                "Store is under heavy write pressure.".to_owned(),
+            )
+                .into_response());
+        }
+
+        let (key, convergent) = match secret {
+            Some(secret) => (secret, true),
+            None => (random_key(state), false),
+        };
+        Ok(Self {
+            options: upload::Options {
+                key,
+                convergent,
+                retention,
+                verify,
+            },
+            block_size,
+        })
+    }
+
+    /// Options for another upload under the same headers, with a fresh random
+    /// key unless a convergence secret was given.
+    fn renew(&self, state: &ApiState) -> Self {
+        let mut options = self.options;
+        if !options.convergent {
+            options.key = random_key(state);
+        }
+        Self {
+            options,
+            block_size: self.block_size,
+        }
+    }
+
+    /// Encode the content and notify webhooks. A persistent convergent upload
+    /// of content that is already being encoded waits for and shares that
+    /// result.
+    async fn encode(
+        &self,
+        state: &ApiState,
+        content: &[u8],
+        block_size: BlockSize,
+    ) -> Result<Arc<Encoded>, ApsisError> {
+        let encode = || {
+            task::block_in_place(|| {
+                upload::encode_content(state, &mut &content[..], block_size, &self.options)
+            })
+            .map(|encoded| {
+                state.webhooks.notify_upload(&state.tracker, &encoded);
+                Arc::new(encoded)
+            })
+        };
+        if self.coalesces() {
+            state
+                .in_flight
+                .coalesce(self.coalescing_id(content, block_size), encode)
+                .await
+        } else {
+            encode()
+        }
+    }
+
+    /// Block size for content of the declared type and length, unless the
+    /// client asked for one.
+    fn block_size(&self, content_type: Option<&str>, length: Option<usize>) -> BlockSize {
+        self.block_size
+            .unwrap_or_else(|| block_size_for(content_type, length))
+    }
+
+    /// Whether concurrent uploads of the same content share one encoding,
+    /// which needs all of the content up front.
+    fn coalesces(&self) -> bool {
+        self.options.convergent && self.options.retention == Retention::Persistent
+    }
+
+    /// Identifies a coalescing upload by its content and everything else that
+    /// changes the encoding, so only identical encodings are shared.
+    fn coalescing_id(&self, content: &[u8], block_size: BlockSize) -> Reference {
+        let block_size: u8 = match block_size {
+            BlockSize::Size1KiB => 0,
+            BlockSize::Size32KiB => 1,
+        };
+        let mut hasher = HashAlgorithm::ERIS.hasher(Some(&self.options.key));
+        hasher.update(&[block_size, self.options.verify as u8]);
+        hasher.update(content);
+        let mut id = Reference::default();
+        id.copy_from_slice(hasher.finalize().as_bytes());
+        id
+    }
+
+    /// Encode content as its chunks arrive, as from a multipart field, and
+    /// notify webhooks. At most `upload_buffer` chunks are held in memory,
+    /// after which reading from the client waits for the encoder.
+    async fn encode_stream<S>(
+        &self,
+        state: &ApiState,
+        chunks: S,
+        block_size: BlockSize,
+    ) -> Result<Arc<Encoded>, ApsisError>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        let mut chunks = std::pin::pin!(chunks);
+        let (tx, rx) = mpsc::channel(state.upload_buffer);
+        let encoder = {
+            let state = state.clone();
+            let options = self.options;
+            task::spawn_blocking(move || {
+                let mut content = ChannelReader {
+                    rx,
+                    chunk: Bytes::new(),
+                };
+                upload::encode_content(&state, &mut content, block_size, &options)
+            })
+        };
+        loop {
+            let Some(chunk) = chunks.next().await else {
+                break;
+            };
+            let failed = chunk.is_err();
+            // The encoder stops receiving once it has failed
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+        drop(tx);
+        let encoded = encoder
+            .await
+            .map_err(|err| ApsisErrorKind::Encode(err.to_string()))??;
+        state.webhooks.notify_upload(&state.tracker, &encoded);
+        Ok(Arc::new(encoded))
+    }
+}
+
+/// Whether content of this media type is usually large enough to be better
+/// served by 32 KiB blocks.
+fn is_bulky(media_type: &str) -> bool {
+    let media_type = media_type.to_ascii_lowercase();
+    ["audio/", "image/", "video/"]
+        .iter()
+        .any(|prefix| media_type.starts_with(prefix))
+        || [
+            "application/gzip",
+            "application/vnd.rar",
+            "application/x-7z-compressed",
+            "application/x-bzip2",
+            "application/x-tar",
+            "application/x-xz",
+            "application/zip",
+            "application/zstd",
+        ]
+        .contains(&media_type.as_str())
+}
+
+/// Block size for content of a declared media type and, if known, length:
+/// media and archives use 32 KiB blocks, while small documents fit a single
+/// 1 KiB block. Content of unknown length may be arbitrarily large, so it
+/// uses 32 KiB blocks too.
+fn block_size_for(content_type: Option<&str>, length: Option<usize>) -> BlockSize {
+    let media_type = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim);
+    match (media_type, length) {
+        (Some(media_type), _) if is_bulky(media_type) => BlockSize::Size32KiB,
+        (_, Some(length)) if length >= 1000 => BlockSize::Size32KiB,
+        (_, None) => BlockSize::Size32KiB,
+        _ => BlockSize::Size1KiB,
+    }
+}
+
 #[debug_handler]
 pub async fn resource_to_name(
-    State(mut state): State<ApiState>,
+    State(state): State<ApiState>,
+    headers: HeaderMap,
     body: Content,
-) -> impl IntoResponse {
-    match body {
+) -> Response {
+    let upload = match Upload::from_headers(&state, &headers) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    let response = match body {
         Content::Json(json) => {
-            let mut key = [0u8; 32];
-            state.rng.fill_bytes(&mut key);
-            let write_block = move |block: BlockWithReference| -> Result<usize, BlockStorageError> {
-                let res = state
-                    .store
-                    .write_block(block.reference, block.block)
-                    .map_err(|_err| io::Error::other("Failed to write block to database."));
-                let id = utils::try_ref_to_id(&block.reference)
-                    .map_err(|err| io::Error::other(err.to_string()))?;
-                let dht = state.dht.clone();
-                state.tracker.spawn(async move {
-                    let _ = dht
-                        .announce_peer(id, None)
-                        .map_err(|_err| io::Error::other("Failed to announce block peer."));
-                });
-
-                res
-            };
             let bytes = json.to_string();
-            let block_size = if bytes.as_bytes().len() < 1000 {
-                BlockSize::Size1KiB
-            } else {
-                BlockSize::Size32KiB
-            };
-            match encode(&mut bytes.as_bytes(), &key, block_size, &write_block) {
-                Ok(capability) => (StatusCode::CREATED, capability.to_urn()),
-                Err(err) => (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()),
+            let block_size = upload.block_size(Some("application/json"), Some(bytes.len()));
+            match upload.encode(&state, bytes.as_bytes(), block_size).await {
+                Ok(encoded) => (StatusCode::CREATED, encoded.capability.to_urn()),
+                Err(err) => encode_failure(err, "Failed to encode JSON."),
             }
         }
         Content::File(mut multipart) => {
-            let mut key = [0u8; 32];
-            state.rng.fill_bytes(&mut key);
-            let write_block = move |block: BlockWithReference| -> Result<usize, BlockStorageError> {
-                let res = state
-                    .store
-                    .write_block(block.reference, block.block)
-                    .map_err(|_err| io::Error::other("Failed to write block to database."));
-                let id = utils::try_ref_to_id(&block.reference)
-                    .map_err(|err| io::Error::other(err.to_string()))?;
-                let dht = state.dht.clone();
-                state.tracker.spawn(async move {
-                    let _ = dht
-                        .announce_peer(id, state.port)
-                        .map_err(|_err| io::Error::other("Failed to announce block peer."));
-                });
-                res
-            };
-
             if let Ok(Some(field)) = multipart.next_field().await {
-                if let Ok(bytes) = field.bytes().await {
-                    if let Ok(capability) =
-                        encode(&mut bytes.reader(), &key, BlockSize::Size1KiB, &write_block)
-                    {
-                        (StatusCode::CREATED, capability.to_urn())
-                    } else {
-                        (
+                let content_type = field.content_type().map(str::to_owned);
+                let encoded = if upload.coalesces() {
+                    let Ok(bytes) = field.bytes().await else {
+                        Failure::InvalidUpload.record();
+                        return (
                             StatusCode::UNPROCESSABLE_ENTITY,
-                            "Failed to create capability.".to_owned(),
+                            "Failed to extract bytes from multipart files.".to_owned(),
                         )
-                    }
+                            .into_response();
+                    };
+                    let block_size = upload.block_size(content_type.as_deref(), Some(bytes.len()));
+                    upload.encode(&state, &bytes, block_size).await
                 } else {
-                    (
-                        StatusCode::UNPROCESSABLE_ENTITY,
-                        "Failed to extract bytes from multipart files.".to_owned(),
-                    )
+                    // The length of a streamed file isn't known up front, but
+                    // the request's declared length bounds it
+                    let length = headers
+                        .get(CONTENT_LENGTH)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse().ok());
+                    let block_size = upload.block_size(content_type.as_deref(), length);
+                    let chunks = field.map(|chunk| chunk.map_err(io::Error::other));
+                    upload.encode_stream(&state, chunks, block_size).await
+                };
+                match encoded {
+                    Ok(encoded) => (StatusCode::CREATED, encoded.capability.to_urn()),
+                    Err(err) => encode_failure(err, "Failed to create capability."),
                 }
             } else {
+                Failure::InvalidUpload.record();
                 (
                     StatusCode::UNPROCESSABLE_ENTITY,
                     "Failed to read file.".to_owned(),
                 )
             }
         }
+    };
+    response.into_response()
+}
+
+/// Outcome of one line of a streamed batch upload, by its line number.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchLine {
+    Encoded { line: usize, urn: String },
+    Failed { line: usize, error: String },
+}
+
+/// Encode one line of a streamed batch upload as a JSON document.
+async fn encode_line(state: &ApiState, upload: Upload, line: usize, json: &[u8]) -> BatchLine {
+    let Ok(json) = serde_json::from_slice::<Value>(json) else {
+        Failure::NotJson.record();
+        return BatchLine::Failed {
+            line,
+            error: "Line is not JSON.".to_owned(),
+        };
+    };
+    let bytes = json.to_string();
+    let block_size = upload.block_size(Some("application/json"), Some(bytes.len()));
+    match upload.encode(state, bytes.as_bytes(), block_size).await {
+        Ok(encoded) => BatchLine::Encoded {
+            line,
+            urn: encoded.capability.to_urn(),
+        },
+        Err(err) => BatchLine::Failed {
+            line,
+            error: encode_failure(err, "Failed to encode JSON.").1,
+        },
     }
 }
 
+/// Encode each line of an NDJSON body as its own JSON upload while the body
+/// streams in, streaming back a `{ line, urn }` or `{ line, error }` result
+/// per line as each completes. At most `BATCH_CONCURRENCY` lines are encoded
+/// at once, and the body isn't read further until one of them is done.
 #[debug_handler]
-pub async fn name_to_resource(
+pub async fn batch_stream(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    DynamicQuery(query): DynamicQuery,
-) -> impl IntoResponse {
-    let read_block = move |reference: Reference| -> Result<Vec<u8>, BlockStorageError> {
-        if let Some(block) = state
-            .store
-            .read_block(reference)
-            .map_err(|_err| io::Error::other("Failed to read block from database."))?
-        {
-            Ok(block)
-        } else {
-            utils::fetch_block(reference, &state.dht, true)
-                .map_err(|_err| io::Error::other("Failed to fetch block."))
+    body: Body,
+) -> Response {
+    let upload = match Upload::from_headers(&state, &headers) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    let mut reader = StreamReader::new(
+        body.into_data_stream()
+            .map(|chunk| chunk.map_err(io::Error::other)),
+    );
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+    let send = |tx: mpsc::Sender<io::Result<Bytes>>, result: BatchLine| async move {
+        if let Ok(mut line) = serde_json::to_vec(&result) {
+            line.push(b'\n');
+            let _ = tx.send(Ok(line.into())).await;
         }
     };
-    if let Some(capability) = ReadCapability::from_urn(query.clone()) {
-        let mut buf = BytesMut::new().writer();
-        if let Ok(_size) = task::block_in_place(|| decode(capability, &mut buf, &read_block)) {
-            let buf = buf.into_inner();
-            match headers.get(ACCEPT) {
-                Some(accept) if accept == "application/json" => {
-                    if let Ok(json) = serde_json::from_slice::<Value>(&buf) {
-                        Json(json).into_response()
-                    } else {
-                        (
-                            StatusCode::UNPROCESSABLE_ENTITY,
-                            "Entity is not JSON".to_owned(),
-                        )
-                            .into_response()
-                    }
+    let tracker = state.tracker.clone();
+    tracker.spawn(async move {
+        let workers = Arc::new(Semaphore::new(BATCH_CONCURRENCY));
+        let mut line = 0;
+        while !tx.is_closed() {
+            let Ok(permit) = workers.clone().acquire_owned().await else {
+                break;
+            };
+            let mut buf = Vec::new();
+            let read = (&mut reader)
+                .take(MAX_BATCH_LINE_BYTES as u64 + 1)
+                .read_until(b'\n', &mut buf)
+                .await;
+            line += 1;
+            match read {
+                Ok(0) => break,
+                Ok(_) if buf.len() > MAX_BATCH_LINE_BYTES => {
+                    Failure::InvalidUpload.record();
+                    let error = format!("Line exceeds {MAX_BATCH_LINE_BYTES} bytes.");
+                    send(tx, BatchLine::Failed { line, error }).await;
+                    break;
+                }
+                Ok(_) if buf.trim_ascii().is_empty() => {}
+                Ok(_) => {
+                    let (state, tx) = (state.clone(), tx.clone());
+                    let upload = upload.renew(&state);
+                    state.tracker.clone().spawn(async move {
+                        send(tx, encode_line(&state, upload, line, &buf).await).await;
+                        drop(permit);
+                    });
+                }
+                Err(err) => {
+                    Failure::InvalidUpload.record();
+                    let error = format!("Failed to read request body: {err}");
+                    send(tx, BatchLine::Failed { line, error }).await;
+                    break;
                 }
-                Some(accept) if accept == "application/octet-stream" => buf.into_response(),
-                Some(accept) if accept == "*/*" => buf.into_response(),
-                None => buf.into_response(),
-                Some(accept) => (
-                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
-                    format!("Unsupported media type {:?}", accept),
-                )
-                    .into_response(),
             }
-        } else {
-            (
-                StatusCode::NOT_FOUND,
-                "Failed to dereference capability.".to_owned(),
-            )
-                .into_response()
         }
-    } else if let Some(reference) = utils::urn_to_ref(query) {
-        if let Ok(block) = read_block(reference) {
-            block.into_response()
-        } else {
-            (StatusCode::NOT_FOUND, "Failed to fetch block.".to_owned()).into_response()
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+/// Decode a capability into memory. Must be called from a blocking context.
+fn decode_content(state: &ApiState, capability: ReadCapability) -> io::Result<Bytes> {
+    let read_block = |reference: Reference| load_block(state, reference, None);
+    let mut buf = BytesMut::new().writer();
+    decode(capability, &mut buf, &read_block)?;
+    Ok(buf.into_inner().freeze())
+}
+
+/// Size of a capability's content, found from the rightmost path of its tree
+/// without decoding it. Must be called from a blocking context.
+fn content_length(state: &ApiState, capability: &ReadCapability) -> io::Result<u64> {
+    let read_block = |reference: Reference| load_block(state, reference, None);
+    Ok(Tree::new(capability, &read_block)
+        .layout()?
+        .content_length())
+}
+
+/// Decode a link record, or `None` for content too large to be one or that
+/// doesn't parse as one. Must be called from a blocking context.
+fn decode_link(state: &ApiState, capability: ReadCapability) -> io::Result<Option<Link>> {
+    if content_length(state, &capability)? > MAX_LINK_BYTES {
+        return Ok(None);
+    }
+    Ok(Link::from_slice(&decode_content(state, capability)?))
+}
+
+fn dereference_failure() -> Response {
+    Failure::DecodeFailure.record();
+    (
+        StatusCode::NOT_FOUND,
+        "Failed to dereference capability.".to_owned(),
+    )
+        .into_response()
+}
+
+fn block_not_found() -> Response {
+    Failure::BlockNotFound.record();
+    (StatusCode::NOT_FOUND, "Failed to fetch block.".to_owned()).into_response()
+}
+
+/// Whether a read failed because the DHT a block could have been fetched from
+/// hasn't bootstrapped, as reported by [`load_block`] however deep among the
+/// error's sources.
+fn dht_not_ready(err: &io::Error) -> bool {
+    let mut source = err
+        .get_ref()
+        .map(|err| err as &(dyn std::error::Error + 'static));
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<ApsisError>()
+            && matches!(err.inner(), ApsisErrorKind::DhtNotReady(_))
+        {
+            return true;
         }
-    } else {
-        (
-            StatusCode::UNPROCESSABLE_ENTITY,
-            "Invalid capability.".to_owned(),
-        )
-            .into_response()
+        source = err.source();
+    }
+    false
+}
+
+/// Response to a read that failed with `err`: `503` if the DHT hasn't
+/// bootstrapped, as the content may well exist, and `not_found` otherwise.
+fn read_failure(err: &io::Error, not_found: impl FnOnce() -> Response) -> Response {
+    if !dht_not_ready(err) {
+        return not_found();
+    }
+    Failure::DhtNotReady.record();
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        "DHT is not ready yet, retry shortly.".to_owned(),
+    )
+        .into_response()
+}
+
+fn invalid_capability() -> Response {
+    Failure::InvalidCapability.record();
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Invalid capability.".to_owned(),
+    )
+        .into_response()
+}
+
+fn block_reads_disabled() -> Response {
+    Failure::InvalidCapability.record();
+    (
+        StatusCode::BAD_REQUEST,
+        "Only capability URNs are served.".to_owned(),
+    )
+        .into_response()
+}
+
+fn log_too_long() -> Response {
+    Failure::InvalidCapability.record();
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Log has more than {MAX_LOG_LINKS} entries."),
+    )
+        .into_response()
+}
+
+fn log_too_large() -> Response {
+    Failure::InvalidCapability.record();
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Log holds more than {MAX_LOG_BYTES} bytes."),
+    )
+        .into_response()
+}
+
+fn invalid_link() -> Response {
+    Failure::InvalidCapability.record();
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Capability is not a log link.".to_owned(),
+    )
+        .into_response()
+}
+
+/// Apply a JSON Merge Patch (RFC 7386) to `target`.
+fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (name, value) in patch {
+            if value.is_null() {
+                target.shift_remove(&name);
+            } else {
+                merge_patch(target.entry(name).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+#[debug_handler]
+pub async fn patch_content(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    DynamicQuery(query): DynamicQuery,
+    Json(patch): Json<Value>,
+) -> Response {
+    let Some(capability) = ReadCapability::from_urn(query) else {
+        return invalid_capability();
+    };
+    let upload = match Upload::from_headers(&state, &headers) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    let decoded = {
+        let state = state.clone();
+        read_blocking(move || decode_content(&state, capability)).await
+    };
+    let Ok(buf) = decoded else {
+        return dereference_failure();
+    };
+    let Ok(mut document) = serde_json::from_slice::<Value>(&buf) else {
+        Failure::NotJson.record();
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Stored content is not JSON.".to_owned(),
+        )
+            .into_response();
+    };
+
+    merge_patch(&mut document, patch);
+    let bytes = document.to_string();
+    let block_size = upload.block_size(Some("application/json"), Some(bytes.len()));
+    match upload.encode(&state, bytes.as_bytes(), block_size).await {
+        Ok(encoded) => (StatusCode::CREATED, encoded.capability.to_urn()).into_response(),
+        Err(err) => encode_failure(err, "Failed to encode JSON.").into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn append_content(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(prev): RawQuery,
+    body: Bytes,
+) -> Response {
+    let upload = match Upload::from_headers(&state, &headers) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    // Only extend existing logs, not arbitrary content
+    let mut head = None;
+    if let Some(prev) = &prev {
+        let Some(capability) = ReadCapability::from_urn(prev.clone()) else {
+            return invalid_capability();
+        };
+        head = Some(capability.root_reference);
+        let link = {
+            let state = state.clone();
+            read_blocking(move || decode_link(&state, capability)).await
+        };
+        match link {
+            Ok(Some(_)) => {}
+            Ok(None) => return invalid_link(),
+            Err(_) => return dereference_failure(),
+        }
+    }
+
+    // With If-Match, only append to the head the client expects, as long as
+    // no other append on this node has extended it. Unconditional appends
+    // claim the head too, so that conditional ones racing them fail.
+    let etag = head.map(|head| format!("\"{}\"", RefEncoding::Base32.encode(&head)));
+    if !conditional::if_match(&headers, etag.as_deref()) {
+        return StatusCode::PRECONDITION_FAILED.into_response();
+    }
+    let claimed = match head {
+        Some(head) => match task::block_in_place(|| state.store.claim_extension(head)) {
+            Ok(claimed) => claimed,
+            Err(err) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+            }
+        },
+        None => false,
+    };
+    if head.is_some() && !claimed && headers.contains_key(IF_MATCH) {
+        return StatusCode::PRECONDITION_FAILED.into_response();
+    }
+
+    let response = append_link(&state, upload, &headers, prev, body).await;
+    if claimed
+        && !response.status().is_success()
+        && let Some(head) = head
+        && let Err(err) = task::block_in_place(|| state.store.release_extension(head))
+    {
+        warn!("Failed to release a log head: {}", err);
+    }
+    response
+}
+
+/// Encode a chunk and the link appending it to `prev`.
+async fn append_link(
+    state: &ApiState,
+    upload: Upload,
+    headers: &HeaderMap,
+    prev: Option<String>,
+    body: Bytes,
+) -> Response {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let block_size = upload.block_size(content_type, Some(body.len()));
+    let chunk = match upload.encode(state, &body, block_size).await {
+        Ok(encoded) => encoded.capability.to_urn(),
+        Err(err) => return encode_failure(err, "Failed to encode chunk.").into_response(),
+    };
+    let Ok(link) = serde_json::to_string(&Link { chunk, prev }) else {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to serialize link.".to_owned(),
+        )
+            .into_response();
+    };
+    match upload
+        .encode(
+            state,
+            link.as_bytes(),
+            block_size_for(Some("application/json"), Some(link.len())),
+        )
+        .await
+    {
+        Ok(encoded) => (StatusCode::CREATED, encoded.capability.to_urn()).into_response(),
+        Err(err) => encode_failure(err, "Failed to encode link.").into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn read_log(
+    State(state): State<ApiState>,
+    DynamicQuery(query): DynamicQuery,
+) -> Response {
+    let Some(head) = ReadCapability::from_urn(query) else {
+        return invalid_capability();
+    };
+    match read_blocking(move || Ok(collect_log(&state, head))).await {
+        Ok(Ok(log)) => log.into_response(),
+        Ok(Err(response)) => response,
+        Err(err) => read_failure(&err, dereference_failure),
+    }
+}
+
+/// Follow the links of a log back to its first entry, then decode its chunks
+/// oldest first. The sizes of the chunks are checked against
+/// [`MAX_LOG_BYTES`] before any is decoded. Must be called from a blocking
+/// context.
+fn collect_log(state: &ApiState, mut head: ReadCapability) -> Result<Bytes, Response> {
+    let failure = |err: io::Error| read_failure(&err, dereference_failure);
+    let (mut chunks, mut total) = (Vec::new(), 0);
+    loop {
+        if chunks.len() == MAX_LOG_LINKS {
+            return Err(log_too_long());
+        }
+        let Some(link) = decode_link(state, head).map_err(failure)? else {
+            return Err(invalid_link());
+        };
+        let Some(chunk) = ReadCapability::from_urn(link.chunk) else {
+            return Err(invalid_link());
+        };
+        total += content_length(state, &chunk).map_err(failure)?;
+        if total > MAX_LOG_BYTES {
+            return Err(log_too_large());
+        }
+        chunks.push(chunk);
+        match link.prev.map(ReadCapability::from_urn) {
+            Some(Some(prev)) => head = prev,
+            Some(None) => return Err(invalid_link()),
+            None => break,
+        }
+    }
+
+    let mut log = BytesMut::with_capacity(total as usize);
+    for chunk in chunks.into_iter().rev() {
+        log.extend_from_slice(&decode_content(state, chunk).map_err(failure)?);
+    }
+    Ok(log.freeze())
+}
+
+/// Keep a block fetched from the DHT and announce it, so that later reads are
+/// served locally and other nodes can fetch it from here.
+fn cache_fetched(state: &ApiState, reference: Reference, block: &[u8]) {
+    match state.store.cache_block(reference, block.to_vec()) {
+        // Without a port, cached blocks are kept but not announced
+        Ok(true) if state.port.is_some() => {
+            let Ok(id) = utils::try_ref_to_id(&reference) else {
+                return;
+            };
+            let dht = state.dht.current();
+            let permits = state.announce_permits.clone();
+            let port = state.port;
+            state.tracker.spawn_blocking(move || {
+                if let Err(err) = upload::announce(&**dht, &permits, &Handle::current(), id, port) {
+                    warn!("Failed to announce cached block: {}", err);
+                }
+            });
+        }
+        Ok(_) => {}
+        Err(err) => warn!("Failed to cache fetched block: {}", err),
+    }
+}
+
+/// Read a block from the local store and the DHT, in the configured order,
+/// giving up on the DHT at `deadline`. Fails with the DHT's
+/// [`ApsisErrorKind::DhtNotReady`] if the block is missing locally and the
+/// DHT hasn't bootstrapped.
+fn load_block(
+    state: &ApiState,
+    reference: Reference,
+    deadline: Option<Instant>,
+) -> Result<Vec<u8>, BlockStorageError> {
+    let local = || read_local(state, reference);
+    // Called from blocking threads, as decoding reads blocks synchronously
+    let remote = || {
+        let fetch = utils::fetch_block(reference, state.dht.current(), true, deadline);
+        let block = Handle::current().block_on(fetch)?;
+        if state.pin_on_fetch {
+            cache_fetched(state, reference, &block);
+        }
+        Ok::<_, ApsisError>(block)
+    };
+    // Failures are recorded once the read is answered
+    let not_found = || io::Error::other("Failed to fetch block.");
+    let missing = |err: ApsisError| match err.inner() {
+        ApsisErrorKind::DhtNotReady(_) => io::Error::other(err),
+        _ => not_found(),
+    };
+    match state.fetch_priority {
+        FetchPriority::LocalFirst => match local()? {
+            Some(block) => Ok(block),
+            None => remote().map_err(missing),
+        },
+        FetchPriority::DhtFirst => match remote() {
+            Ok(block) => Ok(block),
+            Err(err) => local()?.ok_or_else(|| missing(err)),
+        },
+        FetchPriority::LocalOnly => local()?.ok_or_else(not_found),
+        FetchPriority::DhtOnly => remote().map_err(missing),
+    }
+}
+
+/// Run a read on the blocking pool rather than a runtime worker, as blocks
+/// missing locally are fetched from peers synchronously. A panic is reported
+/// as a failed read.
+async fn read_blocking<T, F>(read: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(read).await.map_err(io::Error::other)?
+}
+
+/// Read a block from the local store, verified against its reference unless
+/// disabled.
+fn read_local(state: &ApiState, reference: Reference) -> io::Result<Option<Vec<u8>>> {
+    let block = if state.verify_local_reads {
+        state.store.read_verified_block(reference)
+    } else {
+        state.store.read_block(reference)
+    };
+    block.map_err(|_err| io::Error::other("Failed to read block from database."))
+}
+
+/// Read a single block requested by its reference. Blocks are
+/// content-addressed, so a local copy is as good as any and is used without
+/// going through the fetch priority.
+fn read_raw_block(
+    state: &ApiState,
+    reference: Reference,
+    deadline: Option<Instant>,
+) -> Result<Vec<u8>, BlockStorageError> {
+    match state.fetch_priority {
+        FetchPriority::DhtOnly => load_block(state, reference, deadline),
+        _ => match read_local(state, reference) {
+            Ok(Some(block)) => Ok(block),
+            _ => load_block(state, reference, deadline),
+        },
+    }
+}
+
+/// What conditional reads of a resource are evaluated against.
+struct Validator {
+    /// Strong entity tag of what a read serves: the root reference of a
+    /// capability, distinguishing the JSON representation, or the reference
+    /// of a single block. The key is left out so that it never ends up in
+    /// caches.
+    etag: String,
+    /// The capability's root reference or the block's reference, whose
+    /// presence tells whether the resource exists.
+    reference: Reference,
+}
+
+impl Validator {
+    fn new(state: &ApiState, query: &str, headers: &HeaderMap) -> Option<Self> {
+        let base32 = |reference: &Reference| {
+            base32::encode(base32::Alphabet::Rfc4648 { padding: false }, reference)
+        };
+        if let Some(capability) = ReadCapability::from_urn(query.to_owned()) {
+            let reference = capability.root_reference;
+            let etag = match Representation::negotiate(headers, state.default_accept) {
+                Representation::Bytes => format!("\"{}\"", base32(&reference)),
+                Representation::Json { .. } => format!("\"{}.json\"", base32(&reference)),
+                Representation::Unsupported(_) => return None,
+            };
+            Some(Self { etag, reference })
+        } else if state.strict_capabilities {
+            None
+        } else {
+            let reference = utils::urn_to_ref(query.to_owned())?;
+            Some(Self {
+                etag: format!("\"{}\"", base32(&reference)),
+                reference,
+            })
+        }
+    }
+
+    /// When the resource was first stored on this node, or `None` if that
+    /// isn't known. Fails if the resource doesn't exist.
+    async fn stored_at(&self, state: &ApiState) -> io::Result<Option<u64>> {
+        let (state, reference) = (state.clone(), self.reference);
+        read_blocking(move || {
+            read_raw_block(&state, reference, state.read_deadline())?;
+            state
+                .store
+                .created_at(reference)
+                .map_err(|_err| io::Error::other("Failed to read block from database."))
+        })
+        .await
+    }
+}
+
+/// Answer a read's preconditions, once the resource is known to exist, or tag
+/// the response to the read. Preconditions on a missing resource are ignored,
+/// so that the read answers it as usual.
+async fn with_preconditions<R>(
+    state: &ApiState,
+    headers: &HeaderMap,
+    validator: Option<Validator>,
+    read: impl Future<Output = R>,
+) -> Response
+where
+    R: IntoResponse,
+{
+    let Some(validator) = validator else {
+        return read.await.into_response();
+    };
+    let mut stored_at = None;
+    if conditional::is_conditional(headers)
+        && let Ok(stored) = validator.stored_at(state).await
+    {
+        if let Some(response) = conditional::check(headers, &validator.etag, stored) {
+            return response;
+        }
+        stored_at = stored;
+    }
+    let mut response = read.await.into_response();
+    if !response.status().is_success() {
+        return response;
+    }
+    if let Ok(etag) = HeaderValue::from_str(&validator.etag) {
+        response.headers_mut().insert(ETAG, etag);
+    }
+    let stored_at = stored_at.or_else(|| {
+        task::block_in_place(|| state.store.created_at(validator.reference))
+            .ok()
+            .flatten()
+    });
+    if let Some(stored_at) = stored_at
+        && let Ok(date) = HeaderValue::from_str(&conditional::http_date(stored_at))
+    {
+        response.headers_mut().insert(LAST_MODIFIED, date);
+    }
+    response
+}
+
+#[debug_handler]
+pub async fn name_to_resource(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    DynamicQuery(query): DynamicQuery,
+) -> Response {
+    let validator = Validator::new(&state, &query, &headers);
+    let read = read_resource(state.clone(), &headers, query);
+    with_preconditions(&state, &headers, validator, read).await
+}
+
+async fn read_resource(state: ApiState, headers: &HeaderMap, query: String) -> Response {
+    let deadline = state.read_deadline();
+    let read_block = {
+        let state = state.clone();
+        move |reference: Reference| load_block(&state, reference, deadline)
+    };
+    if let Some(capability) = ReadCapability::from_urn(query.clone()) {
+        let mut representation = Representation::negotiate(headers, state.default_accept);
+        let range =
+            ByteRange::parse(headers).filter(|_| matches!(representation, Representation::Bytes));
+        if let Some(buf) = read_inline(&state, &capability) {
+            return decoded_content(&state, representation, buf, range);
+        }
+        // Map the tree to size the content. If it can't be mapped, the content
+        // is decoded in full below, so that a missing block is still reported
+        // as a 404.
+        let layout = {
+            let (capability, read_block) =
+                (utils::copy_capability(&capability), read_block.clone());
+            read_blocking(move || Tree::new(&capability, &read_block).layout())
+                .await
+                .ok()
+        };
+        if let Representation::Json { fallback } = representation
+            && let Some(layout) = &layout
+            && layout.content_length() > MAX_JSON_BYTES
+        {
+            if !fallback {
+                return json_too_large();
+            }
+            representation = Representation::Bytes;
+        }
+        // Manifests are checked as a whole, so content that may be one is
+        // decoded in full below
+        if let Some(layout) =
+            layout.filter(|layout| !state.checks_manifest(layout.content_length()))
+        {
+            if let Some(range) = range {
+                // Fall back to a full decode below if the range can't be read
+                let partial = {
+                    let (capability, read_block) =
+                        (utils::copy_capability(&capability), read_block.clone());
+                    read_blocking(move || read_partial(&capability, &layout, range, &read_block))
+                        .await
+                };
+                if let Ok(response) = partial {
+                    return response;
+                }
+            } else if let Representation::Bytes = representation {
+                let length = layout.content_length();
+                if state.prefetch_blocks == 0 {
+                    return stream_content(capability, read_block, length);
+                }
+                let prefetch =
+                    Prefetch::start(&capability, read_block.clone(), state.prefetch_blocks);
+                let read_block = move |reference| prefetch.read(reference, &read_block);
+                return stream_content(capability, read_block, length);
+            }
+        }
+        let decoded = read_blocking(move || {
+            let mut buf = BytesMut::new().writer();
+            decode(capability, &mut buf, &read_block)?;
+            Ok(buf.into_inner().freeze())
+        })
+        .await;
+        match decoded {
+            Ok(buf) => decoded_content(&state, representation, buf, range),
+            Err(err) => read_failure(&err, dereference_failure),
+        }
+    } else if let Some(reference) = utils::urn_to_ref(query) {
+        if state.strict_capabilities {
+            return block_reads_disabled();
+        }
+        let block = {
+            let state = state.clone();
+            read_blocking(move || read_raw_block(&state, reference, deadline)).await
+        };
+        match block {
+            Ok(block) => (
+                [
+                    (CONTENT_TYPE, "application/octet-stream"),
+                    (CACHE_CONTROL, "public, max-age=31536000, immutable"),
+                ],
+                block,
+            )
+                .into_response(),
+            Err(err) => read_failure(&err, block_not_found),
+        }
+    } else {
+        invalid_capability()
+    }
+}
+
+/// Decoded content of a capability from the inline cache, if enabled and
+/// holding it.
+fn read_inline(state: &ApiState, capability: &ReadCapability) -> Option<Bytes> {
+    state.inline_max_bytes?;
+    let content = task::block_in_place(|| {
+        state
+            .store
+            .read_inline(capability.root_reference, &capability.root_key)
+    });
+    match content {
+        Ok(content) => content.map(Bytes::from),
+        Err(err) => {
+            warn!("Failed to read inline content: {}", err);
+            None
+        }
+    }
+}
+
+/// Strip the body of a response to a read to answer `HEAD`, keeping its
+/// length.
+fn without_body(response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+    if let Some(length) = body.size_hint().exact() {
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(length));
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+/// Serve decoded content in the negotiated representation, unless it is a
+/// manifest failing its signature check.
+fn decoded_content(
+    state: &ApiState,
+    representation: Representation,
+    buf: Bytes,
+    range: Option<ByteRange>,
+) -> Response {
+    let verified = match verify_manifest(&buf, state.manifest_key.as_ref()) {
+        Ok(verified) => verified,
+        Err(response) => return response,
+    };
+    let response = match representation {
+        Representation::Json { fallback } => json_content(buf, fallback),
+        Representation::Bytes => full_content(buf, range),
+        Representation::Unsupported(accept) => {
+            Failure::UnsupportedMedia.record();
+            (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("Unsupported media type {:?}", accept),
+            )
+                .into_response()
+        }
+    };
+    if verified && response.status().is_success() {
+        ([(MANIFEST_VERIFIED_HEADER, "true")], response).into_response()
+    } else {
+        response
+    }
+}
+
+/// Answer `HEAD` on the read route from the tree's layout rather than by
+/// decoding the content, so that only the rightmost path of the tree is read.
+/// Content served as JSON or checked as a manifest is decoded, as the
+/// response depends on it.
+#[debug_handler]
+pub async fn head_resource(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    DynamicQuery(query): DynamicQuery,
+) -> Response {
+    let validator = Validator::new(&state, &query, &headers);
+    let read = head_layout(state.clone(), &headers, query);
+    with_preconditions(&state, &headers, validator, read).await
+}
+
+async fn head_layout(state: ApiState, headers: &HeaderMap, query: String) -> Response {
+    let deadline = state.read_deadline();
+    let read_block = {
+        let state = state.clone();
+        move |reference: Reference| load_block(&state, reference, deadline)
+    };
+    if let Some(capability) = ReadCapability::from_urn(query.clone()) {
+        let mut representation = Representation::negotiate(headers, state.default_accept);
+        if let Representation::Unsupported(_) = representation {
+            Failure::UnsupportedMedia.record();
+            return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+        }
+        let range =
+            ByteRange::parse(headers).filter(|_| matches!(representation, Representation::Bytes));
+        let layout = {
+            let (capability, read_block) =
+                (utils::copy_capability(&capability), read_block.clone());
+            read_blocking(move || Tree::new(&capability, &read_block).layout()).await
+        };
+        let length = match layout {
+            Ok(layout) => layout.content_length(),
+            Err(err) => return read_failure(&err, dereference_failure),
+        };
+        if let Representation::Json { fallback } = representation
+            && length > MAX_JSON_BYTES
+        {
+            if !fallback {
+                return json_too_large();
+            }
+            representation = Representation::Bytes;
+        }
+        // Serving content as JSON and checking a manifest's signature both
+        // depend on parsing the content, so decode it as a read would
+        if matches!(representation, Representation::Json { .. }) || state.checks_manifest(length) {
+            let decoded = read_blocking(move || {
+                let mut buf = BytesMut::new().writer();
+                decode(capability, &mut buf, &read_block)?;
+                Ok(buf.into_inner().freeze())
+            })
+            .await;
+            return match decoded {
+                Ok(buf) => without_body(decoded_content(&state, representation, buf, range)),
+                Err(err) => read_failure(&err, dereference_failure),
+            };
+        }
+        match range.map(|range| range.resolve(length)) {
+            Some(Some((start, end))) => (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (ACCEPT_RANGES, "bytes".to_owned()),
+                    (CONTENT_LENGTH, (end - start + 1).to_string()),
+                    (CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, length)),
+                ],
+            )
+                .into_response(),
+            Some(None) => range_not_satisfiable(length),
+            None => [
+                (ACCEPT_RANGES, "bytes".to_owned()),
+                (CONTENT_LENGTH, length.to_string()),
+                (CONTENT_TYPE, "application/octet-stream".to_owned()),
+            ]
+            .into_response(),
+        }
+    } else if let Some(reference) = utils::urn_to_ref(query) {
+        if state.strict_capabilities {
+            return block_reads_disabled();
+        }
+        let block = {
+            let state = state.clone();
+            read_blocking(move || read_raw_block(&state, reference, deadline)).await
+        };
+        match block {
+            Ok(block) => [
+                (CONTENT_LENGTH, block.len().to_string()),
+                (CONTENT_TYPE, "application/octet-stream".to_owned()),
+                (
+                    CACHE_CONTROL,
+                    "public, max-age=31536000, immutable".to_owned(),
+                ),
+            ]
+            .into_response(),
+            Err(err) => read_failure(&err, block_not_found),
+        }
+    } else {
+        invalid_capability()
+    }
+}
+
+/// Serve the configured root capability like any other capability.
+#[debug_handler]
+pub async fn root(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    match state.root_capability.clone() {
+        Some(urn) => name_to_resource(State(state), headers, DynamicQuery(urn))
+            .await
+            .into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            "No root capability configured.".to_owned(),
+        )
+            .into_response(),
+    }
+}
+
+/// Format of a capability's reference listing.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RefsFormat {
+    /// A single JSON array.
+    #[default]
+    Array,
+    /// One JSON string per line.
+    Ndjson,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefsQuery {
+    urn: String,
+    #[serde(default)]
+    format: RefsFormat,
+    /// References to skip before listing.
+    #[serde(default)]
+    offset: usize,
+    /// Maximum number of references to list.
+    limit: Option<usize>,
+    #[serde(default)]
+    encoding: RefEncoding,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EncodingQuery {
+    #[serde(default)]
+    encoding: RefEncoding,
+}
+
+/// List the URNs of every block of a capability, streamed while its tree is
+/// walked so that neither end holds the whole list of a large capability.
+#[debug_handler]
+pub async fn capability_refs(
+    State(state): State<ApiState>,
+    Query(query): Query<RefsQuery>,
+) -> Response {
+    let Some(capability) = ReadCapability::from_urn(query.urn) else {
+        return invalid_capability();
+    };
+    // Fail before streaming if the tree can't be read at all
+    let root = {
+        let (state, reference) = (state.clone(), capability.root_reference);
+        read_blocking(move || load_block(&state, reference, None)).await
+    };
+    if root.is_err() {
+        return dereference_failure();
+    }
+    let content_type = match query.format {
+        RefsFormat::Array => "application/json",
+        RefsFormat::Ndjson => "application/x-ndjson",
+    };
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+    task::spawn_blocking(move || {
+        let read_block = |reference: Reference| load_block(&state, reference, None);
+        let mut writer = BufWriter::new(ChannelWriter(tx));
+        let limit = query.limit.unwrap_or(usize::MAX);
+        let (mut skipped, mut listed) = (0, 0);
+        if let RefsFormat::Array = query.format {
+            let _ = writer.write_all(b"[");
+        }
+        let walked = Tree::new(&capability, &read_block).references(&mut |reference| {
+            if skipped < query.offset {
+                skipped += 1;
+                return Ok(ControlFlow::Continue(()));
+            }
+            if listed == limit {
+                return Ok(ControlFlow::Break(()));
+            }
+            let urn = utils::ref_to_urn_as(&reference, query.encoding);
+            match query.format {
+                RefsFormat::Array if listed > 0 => write!(writer, ",\"{}\"", urn)?,
+                RefsFormat::Array => write!(writer, "\"{}\"", urn)?,
+                RefsFormat::Ndjson => writeln!(writer, "\"{}\"", urn)?,
+            }
+            listed += 1;
+            Ok(ControlFlow::Continue(()))
+        });
+        if walked.is_err() {
+            Failure::DecodeFailure.record();
+            let _ = writer
+                .get_ref()
+                .0
+                .blocking_send(Err(io::Error::other("Failed to walk capability.")));
+        } else {
+            if let RefsFormat::Array = query.format {
+                let _ = writer.write_all(b"]");
+            }
+            let _ = writer.flush();
+        }
+    });
+    (
+        [(CONTENT_TYPE, content_type)],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+/// A capability broken down into its components, for debugging.
+#[derive(Debug, Serialize)]
+struct CapabilityInfo {
+    root_reference: String,
+    root_key: String,
+    level: u8,
+    block_size: usize,
+    children: Vec<String>,
+}
+
+#[debug_handler]
+pub async fn debug_capability(
+    State(state): State<ApiState>,
+    DynamicQuery(query): DynamicQuery,
+) -> Response {
+    let Some(capability) = ReadCapability::from_urn(query) else {
+        return invalid_capability();
+    };
+    let root = {
+        let (state, reference) = (state.clone(), capability.root_reference);
+        read_blocking(move || load_block(&state, reference, None)).await
+    };
+    let Ok(root) = root else {
+        return (
+            StatusCode::NOT_FOUND,
+            "Failed to fetch root block.".to_owned(),
+        )
+            .into_response();
+    };
+    // A level 0 root is the only leaf and has no children
+    let children = if capability.level > 0 {
+        tree::children(&tree::decrypt(
+            &root,
+            &capability.root_key,
+            capability.level,
+        ))
+        .iter()
+        .map(|(reference, _key)| utils::ref_to_urn(reference))
+        .collect()
+    } else {
+        Vec::new()
+    };
+    Json(CapabilityInfo {
+        root_reference: utils::ref_to_urn(&capability.root_reference),
+        root_key: hex::encode(capability.root_key),
+        level: capability.level,
+        block_size: capability.block_size,
+        children,
+    })
+    .into_response()
+}
+
+/// A block reference given in a path by its URN, with or without the `urn:`
+/// prefix.
+fn path_reference(reference: String, encoding: RefEncoding) -> Option<Reference> {
+    let urn = if reference.starts_with("urn:") {
+        reference
+    } else {
+        format!("urn:{reference}")
+    };
+    utils::urn_to_ref_as(urn, encoding)
+}
+
+/// Serve a single block by its reference, for replication from nodes that
+/// don't serve bare block references on the read route.
+#[debug_handler]
+pub async fn admin_block(
+    State(state): State<ApiState>,
+    Path(reference): Path<String>,
+    Query(query): Query<EncodingQuery>,
+) -> Response {
+    let Some(reference) = path_reference(reference, query.encoding) else {
+        return invalid_capability();
+    };
+    let block = {
+        let state = state.clone();
+        read_blocking(move || read_raw_block(&state, reference, state.read_deadline())).await
+    };
+    match block {
+        Ok(block) => ([(CONTENT_TYPE, "application/octet-stream")], block).into_response(),
+        Err(err) => read_failure(&err, block_not_found),
+    }
+}
+
+/// List the root references of the capabilities uploaded here that a block,
+/// given by its URN with or without the `urn:` prefix, belongs to.
+#[debug_handler]
+pub async fn block_capabilities(
+    State(state): State<ApiState>,
+    Path(reference): Path<String>,
+    Query(query): Query<EncodingQuery>,
+) -> Response {
+    let Some(reference) = path_reference(reference, query.encoding) else {
+        return invalid_capability();
+    };
+    match task::block_in_place(|| state.store.owners_of(reference)) {
+        Ok(roots) => Json(
+            roots
+                .iter()
+                .map(|root| utils::ref_to_urn_as(root, query.encoding))
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Number of capability roots listed in a garbage collection report.
+const GC_ROOTS_SAMPLE: usize = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct GcQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Summary of a garbage collection pass.
+#[derive(Debug, Serialize)]
+struct GcReport {
+    dry_run: bool,
+    blocks: usize,
+    bytes: u64,
+    /// Number of uploaded capabilities losing blocks.
+    capabilities: usize,
+    /// A sample of their root references.
+    roots: Vec<String>,
+}
+
+/// Run garbage collection now, or with `dry_run` only report what it would
+/// remove.
+#[debug_handler]
+pub async fn collect_garbage(
+    State(state): State<ApiState>,
+    Query(query): Query<GcQuery>,
+) -> Response {
+    match task::block_in_place(|| {
+        state
+            .store
+            .collect_garbage(state.gc_policy, utils::unix_time(), query.dry_run)
+    }) {
+        Ok(reclaimed) => Json(GcReport {
+            dry_run: query.dry_run,
+            blocks: reclaimed.blocks,
+            bytes: reclaimed.bytes,
+            capabilities: reclaimed.roots.len(),
+            roots: reclaimed
+                .roots
+                .iter()
+                .take(GC_ROOTS_SAMPLE)
+                .map(utils::ref_to_urn)
+                .collect(),
+        })
+        .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// List the URNs of all blocks held locally.
+#[debug_handler]
+pub async fn debug_blocks(State(state): State<ApiState>) -> Response {
+    match task::block_in_place(|| {
+        state
+            .store
+            .iter_references()
+            .map(|reference| reference.map(|reference| utils::ref_to_urn(&reference)))
+            .collect::<Result<Vec<_>, ApsisError>>()
+    }) {
+        Ok(urns) => Json(urns).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Check every stored block against its reference, returning the URNs of
+/// those that don't match.
+#[debug_handler]
+pub async fn debug_verify(State(state): State<ApiState>) -> Response {
+    match task::block_in_place(|| {
+        let mut corrupt = Vec::new();
+        for entry in state.store.iter_blocks() {
+            let (reference, block) = entry?;
+            if !HashAlgorithm::ERIS.verify(&block, &reference) {
+                Failure::IntegrityFailure.record();
+                corrupt.push(utils::ref_to_urn(&reference));
+            }
+        }
+        Ok::<_, ApsisError>(corrupt)
+    }) {
+        Ok(corrupt) => Json(corrupt).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::mock::MockDht;
+    use crate::testing::{self, TestNode};
+    use axum::http::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Encode `content` as an upload with default headers would.
+    async fn upload(state: &ApiState, content: &[u8]) -> Arc<Encoded> {
+        let Ok(upload) = Upload::from_headers(state, &HeaderMap::new()) else {
+            panic!("Default upload headers rejected");
+        };
+        upload
+            .encode(state, content, BlockSize::Size1KiB)
+            .await
+            .unwrap()
+    }
+
+    fn convergent(key: Key, verify: bool) -> Upload {
+        Upload {
+            options: upload::Options {
+                key,
+                convergent: true,
+                retention: Retention::Persistent,
+                verify,
+            },
+            block_size: None,
+        }
+    }
+
+    #[test]
+    fn coalescing_id_covers_encode_options() {
+        let key = rand::random();
+        let content = b"content";
+        let id = convergent(key, false).coalescing_id(content, BlockSize::Size1KiB);
+
+        assert_eq!(
+            convergent(key, false).coalescing_id(content, BlockSize::Size1KiB),
+            id
+        );
+        assert_ne!(
+            convergent(key, false).coalescing_id(content, BlockSize::Size32KiB),
+            id
+        );
+        assert_ne!(
+            convergent(key, true).coalescing_id(content, BlockSize::Size1KiB),
+            id
+        );
+        assert_ne!(
+            convergent(rand::random(), false).coalescing_id(content, BlockSize::Size1KiB),
+            id
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uploads_of_the_same_content_get_distinct_keys() {
+        let node = TestNode::new();
+        let mut keys = HashSet::new();
+        let mut roots = HashSet::new();
+        for _ in 0..100 {
+            let encoded = upload(&node.state, b"same content").await;
+            keys.insert(encoded.capability.root_key);
+            roots.insert(encoded.capability.root_reference);
+        }
+        assert_eq!(keys.len(), 100);
+        assert_eq!(roots.len(), 100);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_uploads_get_distinct_keys() {
+        let node = TestNode::new();
+        let uploads: Vec<_> = (0..64)
+            .map(|_| {
+                // Each request handler gets its own clone of the state
+                let state = node.state.clone();
+                tokio::spawn(async move { upload(&state, b"same content").await })
+            })
+            .collect();
+        let mut keys = HashSet::new();
+        for upload in uploads {
+            keys.insert(upload.await.unwrap().capability.root_key);
+        }
+        assert_eq!(keys.len(), 64);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_writes_hold_back_streamed_uploads() {
+        let mut node = TestNode::new();
+        node.state.upload_buffer = 4;
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let chunks = {
+            let pulled = pulled.clone();
+            tokio_stream::iter(0..64).map(move |_| {
+                pulled.fetch_add(1, Ordering::SeqCst);
+                let chunk: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+                Ok::<_, io::Error>(Bytes::from(chunk))
+            })
+        };
+        let Ok(upload) = Upload::from_headers(&node.state, &HeaderMap::new()) else {
+            panic!("Default upload headers rejected");
+        };
+
+        // Nothing is written while the disk is stalled
+        let stalled = node.state.store.stall_writes();
+        let state = node.state.clone();
+        let encoding = tokio::spawn(async move {
+            upload
+                .encode_stream(&state, chunks, BlockSize::Size1KiB)
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // Besides the buffered chunks, the encoder holds one and the reader
+        // waits to send another
+        assert!(pulled.load(Ordering::SeqCst) <= node.state.upload_buffer + 4);
+
+        drop(stalled);
+        let encoded = encoding.await.unwrap().unwrap();
+        assert_eq!(pulled.load(Ordering::SeqCst), 64);
+        assert_eq!(encoded.bytes, 64 * 1024);
+    }
+
+    /// A manifest of `entries` signed with `key`.
+    fn signed_manifest(entries: &[(&str, &str)], key: &ed25519_dalek::SigningKey) -> Vec<u8> {
+        use ed25519_dalek::Signer;
+        let entries: std::collections::BTreeMap<_, _> = entries.iter().copied().collect();
+        let signature = key.sign(&serde_json::to_vec(&entries).unwrap());
+        serde_json::to_vec(&serde_json::json!({
+            "entries": entries,
+            "signature": hex::encode(signature.to_bytes()),
+        }))
+        .unwrap()
+    }
+
+    /// Read a capability as a request with the `Accept` header `accept` would.
+    async fn read(state: &ApiState, urn: String, accept: &str) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_str(accept).unwrap());
+        name_to_resource(State(state.clone()), headers, DynamicQuery(urn))
+            .await
+            .into_response()
+    }
+
+    /// Answer `HEAD` for a capability with the `Accept` header `accept`.
+    async fn head(state: &ApiState, urn: String, accept: &str) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_str(accept).unwrap());
+        head_resource(State(state.clone()), headers, DynamicQuery(urn)).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn head_answers_like_a_read() {
+        let node = TestNode::new();
+        let json = upload(&node.state, br#"{"key": "value"}"#).await;
+        let bytes = upload(&node.state, b"not json").await;
+        for urn in [json.capability.to_urn(), bytes.capability.to_urn()] {
+            for accept in [
+                "application/json",
+                "application/json, application/octet-stream",
+                "application/octet-stream",
+            ] {
+                let head = head(&node.state, urn.clone(), accept).await;
+                let read = read(&node.state, urn.clone(), accept).await;
+                assert_eq!(head.status(), read.status(), "{accept}");
+                assert_eq!(head.headers()[CONTENT_TYPE], read.headers()[CONTENT_TYPE]);
+                let body = axum::body::to_bytes(read.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                assert_eq!(head.headers()[CONTENT_LENGTH], body.len().to_string());
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn manifests_are_checked_in_every_representation() {
+        let mut node = TestNode::new();
+        let publisher = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        node.state.manifest_key = Some(publisher.verifying_key());
+        let forger = ed25519_dalek::SigningKey::from_bytes(&[8; 32]);
+        let entries = [("index.html", "urn:eris:example")];
+
+        let signed = upload(&node.state, &signed_manifest(&entries, &publisher)).await;
+        let forged = upload(&node.state, &signed_manifest(&entries, &forger)).await;
+        for accept in ["application/json", "application/octet-stream"] {
+            for response in [
+                read(&node.state, signed.capability.to_urn(), accept).await,
+                head(&node.state, signed.capability.to_urn(), accept).await,
+            ] {
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()[MANIFEST_VERIFIED_HEADER], "true");
+            }
+            for response in [
+                read(&node.state, forged.capability.to_urn(), accept).await,
+                head(&node.state, forged.capability.to_urn(), accept).await,
+            ] {
+                assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_blocks_wait_for_the_dht() {
+        let missing = utils::ref_to_urn(&rand::random());
+        let node = TestNode::with_dht(MockDht::unbootstrapped());
+        let response = read(&node.state, missing.clone(), "application/octet-stream").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+
+        let node = TestNode::new();
+        let response = read(&node.state, missing, "application/octet-stream").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn preconditions_only_apply_to_existing_content() {
+        let node = TestNode::new();
+        let stored = upload(&node.state, b"content").await.capability.to_urn();
+        let missing = upload(&TestNode::new().state, b"elsewhere")
+            .await
+            .capability
+            .to_urn();
+        let since = conditional::http_date(utils::unix_time());
+        for (name, value) in [(IF_NONE_MATCH, "*"), (IF_MODIFIED_SINCE, since.as_str())] {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+            let conditional = |urn: &String| {
+                let validator = Validator::new(&node.state, urn, &headers);
+                let read = read_resource(node.state.clone(), &headers, urn.clone());
+                with_preconditions(&node.state, &headers, validator, read)
+            };
+            assert_eq!(
+                conditional(&stored).await.status(),
+                StatusCode::NOT_MODIFIED
+            );
+            assert_eq!(conditional(&missing).await.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn local_reads_stay_prompt_while_remote_reads_wait() {
+        // A peer that accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(peer) = listener.local_addr().unwrap() else {
+            unreachable!("Bound to an IPv4 address");
+        };
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let mut node = TestNode::new();
+        node.state.read_timeout = Some(Duration::from_secs(2));
+        let stored = upload(&node.state, b"content").await.capability.to_urn();
+
+        let remote: Vec<_> = (0..50)
+            .map(|_| {
+                let reference: Reference = rand::random();
+                node.dht
+                    .add_peer(utils::try_ref_to_id(&reference).unwrap(), peer);
+                let state = node.state.clone();
+                let urn = utils::ref_to_urn(&reference);
+                tokio::spawn(
+                    async move { read_resource(state, &HeaderMap::new(), urn).await.status() },
+                )
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let started = Instant::now();
+        let response = read_resource(node.state.clone(), &HeaderMap::new(), stored).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        for read in remote {
+            assert_eq!(read.await.unwrap(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn corrupt_local_blocks_are_refetched() {
+        let node = TestNode::new();
+        let block: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+        let reference = HashAlgorithm::ERIS.hash(&block, None);
+        let mut corrupt = block.clone();
+        corrupt[0] ^= 1;
+        node.state.store.cache_block(reference, corrupt).unwrap();
+        let urn = utils::ref_to_urn(&reference);
+
+        // Without a peer to refetch it from, the block is missing
+        let response = read_resource(node.state.clone(), &HeaderMap::new(), urn.clone()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let peer = testing::serve_blocks(vec![(reference, block.clone())]).await;
+        node.dht
+            .add_peer(utils::try_ref_to_id(&reference).unwrap(), peer);
+        let response = read_resource(node.state.clone(), &HeaderMap::new(), urn).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            node.state.store.read_verified_block(reference).unwrap(),
+            Some(block)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn json_round_trips_without_an_accept_header() {
+        let node = TestNode::new();
+        let json =
+            serde_json::to_vec(&serde_json::json!({ "nonce": rand::random::<u64>() })).unwrap();
+        let urn = upload(&node.state, &json).await.capability.to_urn();
+
+        let bare = read_resource(node.state.clone(), &HeaderMap::new(), urn.clone()).await;
+        for response in [bare, read(&node.state, urn, "*/*").await] {
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, json);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_reads_refuse_oversized_links() {
+        let node = TestNode::new();
+        let chunk = upload(&node.state, b"entry").await.capability.to_urn();
+        let link = serde_json::to_vec(&Link { chunk, prev: None }).unwrap();
+        let read = |link: Arc<Encoded>| {
+            let urn = link.capability.to_urn();
+            read_log(State(node.state.clone()), DynamicQuery(urn))
+        };
+
+        let response = read(upload(&node.state, &link).await).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "entry");
+
+        // Still a valid link once parsed, but too large to be taken for one
+        let mut padded = link;
+        padded.resize(MAX_LINK_BYTES as usize + 1, b' ');
+        let response = read(upload(&node.state, &padded).await).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
     }
 }
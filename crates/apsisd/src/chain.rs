@@ -0,0 +1,41 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Append-only logs built from linked capabilities.
+//!
+//! Each append stores the new chunk as its own capability and a small JSON
+//! link record pointing at the chunk and at the previous link. The URN of
+//! the newest link is the head of the log.
+
+use serde::{Deserialize, Serialize};
+
+/// A link in an append-only log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Link {
+    /// URN of the content appended by this link.
+    pub chunk: String,
+    /// URN of the previous link, absent for the first entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+}
+
+impl Link {
+    /// Parse a decoded link record.
+    pub fn from_slice(content: &[u8]) -> Option<Self> {
+        serde_json::from_slice(content).ok()
+    }
+}
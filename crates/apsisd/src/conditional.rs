@@ -0,0 +1,188 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Conditional requests against content-addressed resources. Content never
+//! changes, so a reference is a strong validator for everything served under
+//! it, and nothing is modified after it was first stored.
+
+use axum::http::{
+    HeaderMap, HeaderName, HeaderValue, StatusCode,
+    header::{ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+};
+use axum::response::{IntoResponse, Response};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::utils;
+
+/// Whether any entity tag listed in the `name` headers matches the strong
+/// `etag`, or `None` without such headers. Weak comparison ignores the `W/`
+/// prefix, strong comparison never matches a weak tag. `*` matches anything.
+fn matches(headers: &HeaderMap, name: HeaderName, etag: &str, weak: bool) -> Option<bool> {
+    let mut values = headers.get_all(name).iter().peekable();
+    values.peek()?;
+    Some(
+        values
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .any(|tag| match tag.strip_prefix("W/") {
+                _ if tag == "*" => true,
+                Some(opaque) => weak && opaque == etag,
+                None => tag == etag,
+            }),
+    )
+}
+
+/// Whether the `If-Match` precondition of a request modifying the resource
+/// with the strong entity tag `etag` holds. Without the resource, `If-Match`
+/// never holds, not even with `*`.
+pub fn if_match(headers: &HeaderMap, etag: Option<&str>) -> bool {
+    match etag {
+        Some(etag) => matches(headers, IF_MATCH, etag, false) != Some(false),
+        None => !headers.contains_key(IF_MATCH),
+    }
+}
+
+/// Whether a request carries any precondition [`check`] evaluates.
+pub fn is_conditional(headers: &HeaderMap) -> bool {
+    [IF_MATCH, IF_NONE_MATCH, IF_MODIFIED_SINCE]
+        .iter()
+        .any(|name| headers.contains_key(name))
+}
+
+/// An HTTP date for a time in seconds since the Unix epoch.
+pub fn http_date(time: u64) -> String {
+    httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(time))
+}
+
+/// Time in seconds since the Unix epoch of a valid `If-Modified-Since` date,
+/// which can't be in the future.
+fn modified_since(headers: &HeaderMap) -> Option<u64> {
+    let date = headers.get(IF_MODIFIED_SINCE)?.to_str().ok()?;
+    let since = httpdate::parse_http_date(date)
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    (since <= utils::unix_time()).then_some(since)
+}
+
+/// Evaluate the preconditions of a `GET` or `HEAD` request for an existing
+/// resource with the strong entity tag `etag`, first stored at `stored_at`
+/// if known, returning the response to send instead when one of them says
+/// so. `If-Modified-Since` is only consulted without `If-None-Match`, and
+/// only holds for dates since the resource was stored.
+pub fn check(headers: &HeaderMap, etag: &str, stored_at: Option<u64>) -> Option<Response> {
+    if matches(headers, IF_MATCH, etag, false) == Some(false) {
+        return Some(StatusCode::PRECONDITION_FAILED.into_response());
+    }
+    let not_modified = match matches(headers, IF_NONE_MATCH, etag, true) {
+        Some(matched) => matched,
+        None => stored_at
+            .zip(modified_since(headers))
+            .is_some_and(|(stored_at, since)| stored_at <= since),
+    };
+    if !not_modified {
+        return None;
+    }
+    let mut response = (StatusCode::NOT_MODIFIED, [(ETAG, etag.to_owned())]).into_response();
+    if let Some(stored_at) = stored_at
+        && let Ok(date) = HeaderValue::from_str(&http_date(stored_at))
+    {
+        response.headers_mut().insert(LAST_MODIFIED, date);
+    }
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAG: &str = "\"abc\"";
+
+    fn headers(pairs: &[(HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    fn status(headers: &HeaderMap, stored_at: Option<u64>) -> Option<StatusCode> {
+        check(headers, TAG, stored_at).map(|response| response.status())
+    }
+
+    #[test]
+    fn if_none_match() {
+        let not_modified = Some(StatusCode::NOT_MODIFIED);
+        for value in ["\"abc\"", "\"x\", \"abc\"", "W/\"abc\"", "*"] {
+            assert_eq!(
+                status(&headers(&[(IF_NONE_MATCH, value)]), None),
+                not_modified
+            );
+        }
+        assert_eq!(status(&headers(&[(IF_NONE_MATCH, "\"x\"")]), None), None);
+    }
+
+    #[test]
+    fn if_match_on_reads() {
+        for value in ["\"abc\"", "\"x\", \"abc\"", "*"] {
+            assert_eq!(status(&headers(&[(IF_MATCH, value)]), None), None);
+        }
+        // Weak tags never match strongly
+        for value in ["\"x\"", "W/\"abc\""] {
+            assert_eq!(
+                status(&headers(&[(IF_MATCH, value)]), None),
+                Some(StatusCode::PRECONDITION_FAILED)
+            );
+        }
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let now = utils::unix_time();
+        let since = |time| headers(&[(IF_MODIFIED_SINCE, &http_date(time))]);
+        assert_eq!(
+            status(&since(now - 10), Some(now - 20)),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(status(&since(now - 20), Some(now - 10)), None);
+        // Without a time the resource was stored, or for a date yet to come
+        assert_eq!(status(&since(now - 10), None), None);
+        assert_eq!(status(&since(now + 3600), Some(now - 10)), None);
+        assert_eq!(
+            status(&headers(&[(IF_MODIFIED_SINCE, "yesterday")]), Some(0)),
+            None
+        );
+    }
+
+    #[test]
+    fn if_none_match_overrides_if_modified_since() {
+        let headers = headers(&[
+            (IF_NONE_MATCH, "\"x\""),
+            (IF_MODIFIED_SINCE, &http_date(utils::unix_time())),
+        ]);
+        assert_eq!(status(&headers, Some(0)), None);
+    }
+
+    #[test]
+    fn if_match_for_modifications() {
+        assert!(if_match(&headers(&[(IF_MATCH, "\"abc\"")]), Some(TAG)));
+        assert!(!if_match(&headers(&[(IF_MATCH, "\"x\"")]), Some(TAG)));
+        // Without the resource, not even `*` matches
+        assert!(!if_match(&headers(&[(IF_MATCH, "*")]), None));
+        assert!(if_match(&HeaderMap::new(), None));
+    }
+}
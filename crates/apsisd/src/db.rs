@@ -14,31 +14,728 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use rocksdb::DB;
+use rocksdb::{ColumnFamily, DB, DEFAULT_COLUMN_FAMILY_NAME, IteratorMode, Options, WriteBatch};
+use std::collections::{BTreeSet, HashSet};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tracing::warn;
 
-use crate::error::Result;
+use crate::error::{ApsisErrorKind, Result};
+use crate::metrics::Failure;
+use crate::utils::{self, HashAlgorithm};
+
+/// Column family mapping references of ephemeral blocks to their expiry time
+/// in seconds since the Unix epoch.
+const EXPIRY_CF: &str = "expiry";
+
+/// Column family mapping references to the time the block was first stored,
+/// in seconds since the Unix epoch.
+const CREATED_CF: &str = "created";
+
+/// Column family mapping references to the time the block was last written
+/// or read and its length, as big-endian `u64`s.
+const ACCESS_CF: &str = "access";
+
+/// Column family of the references of blocks uploaded to this node, which are
+/// exempt from eviction, mapped to the number of uploads that pinned them as
+/// a big-endian `u64`. An empty value counts as one.
+const PINS_CF: &str = "pins";
+
+/// Seconds a block's access time may lag behind its reads, so that reads
+/// don't each write to the store.
+const ACCESS_RESOLUTION: u64 = 60;
+
+/// Column family indexing which capabilities each block belongs to, keyed by
+/// the block's reference followed by the capability's root reference.
+const OWNERS_CF: &str = "owners";
+
+/// Column family caching the decoded content of small capabilities, keyed by
+/// their root reference. Values are the root key followed by the content, so
+/// that the content is only served to readers holding the capability.
+const INLINE_CF: &str = "inline";
+
+/// Column family of the root references of log entries that an append on
+/// this node has extended.
+const EXTENDED_CF: &str = "extended";
+
+/// Percentage of `max_store_bytes` that garbage collection brings the store
+/// down to.
+const STORE_LOW_WATER_PERCENT: u64 = 90;
+
+/// Limits enforced by garbage collection, besides the expiry of ephemeral
+/// blocks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GcPolicy {
+    /// Seconds after which unpinned blocks are removed.
+    pub max_block_age: Option<u64>,
+    /// Bytes of blocks above which the least recently used unpinned blocks
+    /// are removed.
+    pub max_store_bytes: Option<u64>,
+}
+
+/// Blocks removed by a garbage collection pass, or that would be.
+#[derive(Debug, Default)]
+pub struct Reclaimed {
+    pub blocks: usize,
+    pub bytes: u64,
+    /// Root references of the uploaded capabilities the blocks belong to.
+    pub roots: BTreeSet<[u8; 32]>,
+}
+
+/// Value of an access entry for a block of `length` bytes accessed now.
+fn access_entry(length: usize) -> [u8; 16] {
+    let mut entry = [0u8; 16];
+    entry[..8].copy_from_slice(&utils::unix_time().to_be_bytes());
+    entry[8..].copy_from_slice(&(length as u64).to_be_bytes());
+    entry
+}
 
 #[derive(Clone)]
 pub(crate) struct Db {
     inner: Arc<DB>,
+    /// Bytes of content in the inline cache.
+    inline_bytes: Arc<AtomicU64>,
+    /// Held while checking and recording that a log entry is extended.
+    extend_lock: Arc<Mutex<()>>,
+    /// Held while changing pins or deleting blocks that may be pinned, so
+    /// that a block isn't deleted as an upload pins it.
+    pin_lock: Arc<Mutex<()>>,
 }
 
 impl Db {
     pub fn try_open(path: &PathBuf) -> Result<Self> {
-        Ok(Self {
-            inner: Arc::new(DB::open_default(path)?),
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let db = Self {
+            inner: Arc::new(DB::open_cf(
+                &opts,
+                path,
+                [
+                    DEFAULT_COLUMN_FAMILY_NAME,
+                    EXPIRY_CF,
+                    CREATED_CF,
+                    ACCESS_CF,
+                    PINS_CF,
+                    OWNERS_CF,
+                    INLINE_CF,
+                    EXTENDED_CF,
+                ],
+            )?),
+            inline_bytes: Arc::default(),
+            extend_lock: Arc::default(),
+            pin_lock: Arc::default(),
+        };
+        let mut inline_bytes = 0;
+        for entry in db.inner.iterator_cf(db.inline()?, IteratorMode::Start) {
+            inline_bytes += entry?.1.len() as u64;
+        }
+        db.inline_bytes.store(inline_bytes, Ordering::Relaxed);
+        Ok(db)
+    }
+
+    fn cf(&self, name: &str) -> Result<&ColumnFamily> {
+        self.inner.cf_handle(name).ok_or_else(|| {
+            ApsisErrorKind::Database(format!("Missing column family {name}.")).into()
         })
     }
 
-    pub fn write_block(&self, reference: [u8; 32], block: Vec<u8>) -> Result<usize> {
-        let length = block.len();
-        self.inner.put(reference, block)?;
-        Ok(length)
+    fn expiry(&self) -> Result<&ColumnFamily> {
+        self.cf(EXPIRY_CF)
+    }
+
+    fn created(&self) -> Result<&ColumnFamily> {
+        self.cf(CREATED_CF)
+    }
+
+    fn access(&self) -> Result<&ColumnFamily> {
+        self.cf(ACCESS_CF)
+    }
+
+    fn pins(&self) -> Result<&ColumnFamily> {
+        self.cf(PINS_CF)
+    }
+
+    fn owners(&self) -> Result<&ColumnFamily> {
+        self.cf(OWNERS_CF)
+    }
+
+    fn inline(&self) -> Result<&ColumnFamily> {
+        self.cf(INLINE_CF)
+    }
+
+    fn extended(&self) -> Result<&ColumnFamily> {
+        self.cf(EXTENDED_CF)
     }
 
+    /// Record the creation time of a block not stored before.
+    fn record_created(&self, batch: &mut WriteBatch, reference: [u8; 32]) -> Result<()> {
+        let created = self.created()?;
+        if self.inner.get_pinned_cf(created, reference)?.is_none() {
+            batch.put_cf(created, reference, utils::unix_time().to_be_bytes());
+        }
+        Ok(())
+    }
+
+    /// Delete a block and its metadata as part of `batch`.
+    fn remove(&self, batch: &mut WriteBatch, reference: &[u8]) -> Result<()> {
+        batch.delete(reference);
+        batch.delete_cf(self.expiry()?, reference);
+        batch.delete_cf(self.created()?, reference);
+        batch.delete_cf(self.access()?, reference);
+        batch.delete_cf(self.pins()?, reference);
+        batch.delete_cf(self.extended()?, reference);
+        let owners = self.owners()?;
+        for owner in self.inner.prefix_iterator_cf(owners, reference) {
+            let (key, _) = owner?;
+            if !key.starts_with(reference) {
+                break;
+            }
+            batch.delete_cf(owners, key);
+        }
+        let inline = self.inline()?;
+        if let Some(entry) = self.inner.get_pinned_cf(inline, reference)? {
+            batch.delete_cf(inline, reference);
+            self.inline_bytes
+                .fetch_sub(entry.len() as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn lock_pins(&self) -> MutexGuard<'_, ()> {
+        self.pin_lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hold back writes of uploaded blocks until the guard is dropped, as a
+    /// slow disk would.
+    #[cfg(test)]
+    pub fn stall_writes(&self) -> MutexGuard<'_, ()> {
+        self.lock_pins()
+    }
+
+    /// Number of uploads holding a pin on a block.
+    fn pin_count(&self, reference: [u8; 32]) -> Result<u64> {
+        Ok(match self.inner.get_pinned_cf(self.pins()?, reference)? {
+            None => 0,
+            Some(count) if count.is_empty() => 1,
+            Some(count) => u64::from_be_bytes(count.as_ref().try_into()?),
+        })
+    }
+
+    /// Store and pin a block, clearing any expiry left by an earlier
+    /// ephemeral upload. Returns whether the block wasn't stored before.
+    pub fn write_block(&self, reference: [u8; 32], block: Vec<u8>) -> Result<bool> {
+        let _lock = self.lock_pins();
+        let added = self.inner.get_pinned(reference)?.is_none();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.access()?, reference, access_entry(block.len()));
+        batch.put(reference, block);
+        batch.delete_cf(self.expiry()?, reference);
+        let pins = self.pin_count(reference)? + 1;
+        batch.put_cf(self.pins()?, reference, pins.to_be_bytes());
+        self.record_created(&mut batch, reference)?;
+        self.inner.write(batch)?;
+        Ok(added)
+    }
+
+    /// Store a block that is removed by [`Db::collect_garbage`] after
+    /// `expires_at`. Blocks already stored without an expiry are kept as is,
+    /// and those with one keep the later expiry. Returns whether the block
+    /// wasn't stored before.
+    pub fn write_expiring_block(
+        &self,
+        reference: [u8; 32],
+        block: Vec<u8>,
+        expires_at: u64,
+    ) -> Result<bool> {
+        let _lock = self.lock_pins();
+        let expiry = self.expiry()?;
+        if self.inner.get_pinned(reference)?.is_some() {
+            if let Some(current) = self.inner.get_pinned_cf(expiry, reference)?
+                && u64::from_be_bytes(current.as_ref().try_into()?) < expires_at
+            {
+                self.inner
+                    .put_cf(expiry, reference, expires_at.to_be_bytes())?;
+            }
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.access()?, reference, access_entry(block.len()));
+        batch.put(reference, block);
+        batch.put_cf(expiry, reference, expires_at.to_be_bytes());
+        self.record_created(&mut batch, reference)?;
+        self.inner.write(batch)?;
+        Ok(true)
+    }
+
+    /// Pin a block that is already stored, as [`Db::write_block`] would
+    /// without writing it again. Returns whether the block was stored.
+    pub fn pin_block(&self, reference: [u8; 32]) -> Result<bool> {
+        let _lock = self.lock_pins();
+        if self.inner.get_pinned(reference)?.is_none() {
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(self.expiry()?, reference);
+        let pins = self.pin_count(reference)? + 1;
+        batch.put_cf(self.pins()?, reference, pins.to_be_bytes());
+        self.inner.write(batch)?;
+        Ok(true)
+    }
+
+    /// Drop a pin taken by [`Db::write_block`] or [`Db::pin_block`] for an
+    /// upload that failed.
+    pub fn unpin_block(&self, reference: [u8; 32]) -> Result<()> {
+        let _lock = self.lock_pins();
+        let pins = self.pins()?;
+        match self.pin_count(reference)? {
+            0 => {}
+            1 => self.inner.delete_cf(pins, reference)?,
+            count => self
+                .inner
+                .put_cf(pins, reference, (count - 1).to_be_bytes())?,
+        }
+        Ok(())
+    }
+
+    /// Delete a block and its metadata unless an upload pins it. Returns
+    /// whether it was deleted.
+    pub fn delete_unpinned_block(&self, reference: [u8; 32]) -> Result<bool> {
+        let _lock = self.lock_pins();
+        if self.pin_count(reference)? > 0 {
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::default();
+        self.remove(&mut batch, &reference)?;
+        self.inner.write(batch)?;
+        Ok(true)
+    }
+
+    /// Store a block fetched from another node and checked against its
+    /// reference, unless it is already stored intact. A stored copy that no
+    /// longer matches its reference is replaced. Cached blocks aren't pinned,
+    /// so they can be evicted by store size. Returns whether the block was
+    /// added or replaced.
+    pub fn cache_block(&self, reference: [u8; 32], block: Vec<u8>) -> Result<bool> {
+        if let Some(stored) = self.inner.get_pinned(reference)?
+            && HashAlgorithm::ERIS.verify(&stored, &reference)
+        {
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.access()?, reference, access_entry(block.len()));
+        batch.put(reference, block);
+        self.record_created(&mut batch, reference)?;
+        self.inner.write(batch)?;
+        Ok(true)
+    }
+
+    /// Update a block's access time, unless it was updated in the last
+    /// `ACCESS_RESOLUTION` seconds.
+    fn touch(&self, reference: [u8; 32], length: usize) -> Result<()> {
+        let access = self.access()?;
+        if let Some(entry) = self.inner.get_pinned_cf(access, reference)?
+            && let Some(accessed) = entry.get(..8)
+            && u64::from_be_bytes(accessed.try_into()?) + ACCESS_RESOLUTION > utils::unix_time()
+        {
+            return Ok(());
+        }
+        Ok(self.inner.put_cf(access, reference, access_entry(length))?)
+    }
+
+    /// References of all stored blocks, excluding metadata.
+    pub fn iter_references(&self) -> impl Iterator<Item = Result<[u8; 32]>> + '_ {
+        self.iter_blocks()
+            .map(|entry| entry.map(|(reference, _block)| reference))
+    }
+
+    /// All stored blocks with their references, excluding metadata.
+    pub fn iter_blocks(&self) -> impl Iterator<Item = Result<([u8; 32], Vec<u8>)>> + '_ {
+        // Blocks live in the default column family, metadata in the others
+        self.inner.iterator(IteratorMode::Start).map(|entry| {
+            let (reference, block) = entry?;
+            Ok((reference.as_ref().try_into()?, block.into_vec()))
+        })
+    }
+
+    /// Record that `blocks` belong to the capability with root reference
+    /// `root`.
+    pub fn add_owner(&self, root: [u8; 32], blocks: &[[u8; 32]]) -> Result<()> {
+        let owners = self.owners()?;
+        let mut batch = WriteBatch::default();
+        for block in blocks {
+            batch.put_cf(owners, [*block, root].concat(), []);
+        }
+        Ok(self.inner.write(batch)?)
+    }
+
+    /// Root references of the capabilities a block belongs to.
+    pub fn owners_of(&self, reference: [u8; 32]) -> Result<Vec<[u8; 32]>> {
+        let mut roots = Vec::new();
+        for owner in self.inner.prefix_iterator_cf(self.owners()?, reference) {
+            let (key, _) = owner?;
+            let Some(root) = key.strip_prefix(&reference[..]) else {
+                break;
+            };
+            roots.push(root.try_into()?);
+        }
+        Ok(roots)
+    }
+
+    /// Cache the decoded `content` of the capability with root reference
+    /// `root` and root key `key`, unless the cache would grow beyond
+    /// `max_bytes`. The entry is removed along with the root block. Returns
+    /// whether the content was added.
+    pub fn cache_inline(
+        &self,
+        root: [u8; 32],
+        key: &[u8],
+        content: &[u8],
+        max_bytes: u64,
+    ) -> Result<bool> {
+        let inline = self.inline()?;
+        let length = (key.len() + content.len()) as u64;
+        if self.inner.get_pinned_cf(inline, root)?.is_some()
+            || self.inline_bytes.load(Ordering::Relaxed) + length > max_bytes
+        {
+            return Ok(false);
+        }
+        self.inner.put_cf(inline, root, [key, content].concat())?;
+        self.inline_bytes.fetch_add(length, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Decoded content of the capability with root reference `root` and root
+    /// key `key`, if it is in the inline cache.
+    pub fn read_inline(&self, root: [u8; 32], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(entry) = self.inner.get_pinned_cf(self.inline()?, root)? else {
+            return Ok(None);
+        };
+        Ok(entry.strip_prefix(key).map(<[u8]>::to_vec))
+    }
+
+    /// Record that the log entry with root reference `head` is being
+    /// extended, unless it already was. Returns whether it wasn't.
+    pub fn claim_extension(&self, head: [u8; 32]) -> Result<bool> {
+        let extended = self.extended()?;
+        let _lock = self
+            .extend_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if self.inner.get_pinned_cf(extended, head)?.is_some() {
+            return Ok(false);
+        }
+        self.inner.put_cf(extended, head, [])?;
+        Ok(true)
+    }
+
+    /// Undo [`Db::claim_extension`] for an append that failed.
+    pub fn release_extension(&self, head: [u8; 32]) -> Result<()> {
+        Ok(self.inner.delete_cf(self.extended()?, head)?)
+    }
+
+    /// When a block was first stored, in seconds since the Unix epoch.
+    pub fn created_at(&self, reference: [u8; 32]) -> Result<Option<u64>> {
+        self.inner
+            .get_pinned_cf(self.created()?, reference)?
+            .map(|created| Ok(u64::from_be_bytes(created.as_ref().try_into()?)))
+            .transpose()
+    }
+
+    /// Read a block, updating its access time at most every
+    /// `ACCESS_RESOLUTION` seconds.
     pub fn read_block(&self, reference: [u8; 32]) -> Result<Option<Vec<u8>>> {
-        self.inner.get(reference).map_err(|err| err.into())
+        let block = self.inner.get(reference)?;
+        if let Some(block) = &block
+            && let Err(err) = self.touch(reference, block.len())
+        {
+            warn!("Failed to update block access time: {}", err);
+        }
+        Ok(block)
+    }
+
+    /// Read a block like [`Db::read_block`], but treat it as missing if it no
+    /// longer matches its reference, as when the database is corrupted.
+    pub fn read_verified_block(&self, reference: [u8; 32]) -> Result<Option<Vec<u8>>> {
+        let block = self.read_block(reference)?;
+        if let Some(block) = &block
+            && !HashAlgorithm::ERIS.verify(block, &reference)
+        {
+            Failure::IntegrityFailure.record();
+            warn!(
+                "Stored block {} doesn't match its reference",
+                utils::ref_to_urn(&reference)
+            );
+            return Ok(None);
+        }
+        Ok(block)
+    }
+
+    /// Delete ephemeral blocks that expired before `now` and unpinned blocks
+    /// older than the policy's maximum age. Then, once the remaining blocks exceed
+    /// its maximum store size, delete unpinned blocks in least recently used
+    /// order until they fit in `STORE_LOW_WATER_PERCENT` of it. With
+    /// `dry_run`, nothing is deleted and only what would be is reported.
+    pub fn collect_garbage(&self, policy: GcPolicy, now: u64, dry_run: bool) -> Result<Reclaimed> {
+        let mut doomed = HashSet::new();
+        self.select_where(self.expiry()?, |expires_at| expires_at <= now, &mut doomed)?;
+        if let Some(max_block_age) = policy.max_block_age {
+            let cutoff = now.saturating_sub(max_block_age);
+            let mut aged = HashSet::new();
+            self.select_where(self.created()?, |created| created < cutoff, &mut aged)?;
+            for reference in aged {
+                if self.pin_count(reference)? == 0 {
+                    doomed.insert(reference);
+                }
+            }
+        }
+        if let Some(max_bytes) = policy.max_store_bytes {
+            self.select_least_recently_used(max_bytes, &mut doomed)?;
+        }
+
+        // An upload may have pinned a selected block since, so check again
+        // while no more pins can be taken
+        let _lock = self.lock_pins();
+        let mut reclaimed = Reclaimed::default();
+        let mut batch = WriteBatch::default();
+        for reference in doomed {
+            if self.pin_count(reference)? > 0 {
+                continue;
+            }
+            if let Some(block) = self.inner.get_pinned(reference)? {
+                reclaimed.blocks += 1;
+                reclaimed.bytes += block.len() as u64;
+            }
+            reclaimed.roots.extend(self.owners_of(reference)?);
+            if !dry_run {
+                self.remove(&mut batch, &reference)?;
+            }
+        }
+        if !dry_run {
+            self.inner.write(batch)?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Once the blocks stored, besides those already `doomed`, exceed
+    /// `max_bytes`, add unpinned ones to `doomed` in least recently used order
+    /// until the rest fit in `STORE_LOW_WATER_PERCENT` of it.
+    fn select_least_recently_used(
+        &self,
+        max_bytes: u64,
+        doomed: &mut HashSet<[u8; 32]>,
+    ) -> Result<()> {
+        let pins = self.pins()?;
+        let mut total = 0;
+        let mut candidates = Vec::new();
+        for entry in self.inner.iterator_cf(self.access()?, IteratorMode::Start) {
+            let (reference, entry) = entry?;
+            let reference: [u8; 32] = reference.as_ref().try_into()?;
+            if doomed.contains(&reference) {
+                continue;
+            }
+            let entry: [u8; 16] = entry.as_ref().try_into()?;
+            let accessed = u64::from_be_bytes(entry[..8].try_into()?);
+            let length = u64::from_be_bytes(entry[8..].try_into()?);
+            total += length;
+            if self.inner.get_pinned_cf(pins, reference)?.is_none() {
+                candidates.push((accessed, length, reference));
+            }
+        }
+        if total <= max_bytes {
+            return Ok(());
+        }
+
+        let target_bytes = max_bytes / 100 * STORE_LOW_WATER_PERCENT;
+        candidates.sort_unstable_by_key(|(accessed, _, _)| *accessed);
+        for (_, length, reference) in candidates {
+            if total <= target_bytes {
+                break;
+            }
+            doomed.insert(reference);
+            total -= length;
+        }
+        Ok(())
+    }
+
+    /// Add the blocks whose timestamp in the column family `cf` matches
+    /// `predicate` to `doomed`.
+    fn select_where<P>(
+        &self,
+        cf: &ColumnFamily,
+        predicate: P,
+        doomed: &mut HashSet<[u8; 32]>,
+    ) -> Result<()>
+    where
+        P: Fn(u64) -> bool,
+    {
+        for entry in self.inner.iterator_cf(cf, IteratorMode::Start) {
+            let (reference, timestamp) = entry?;
+            if predicate(u64::from_be_bytes(timestamp.as_ref().try_into()?)) {
+                doomed.insert(reference.as_ref().try_into()?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether RocksDB is stopping or delaying writes, or its compaction
+    /// backlog is above `max_pending_compaction_bytes`.
+    pub fn is_overloaded(&self, max_pending_compaction_bytes: u64) -> Result<bool> {
+        let property =
+            |name: &str| -> Result<u64> { Ok(self.inner.property_int_value(name)?.unwrap_or(0)) };
+        Ok(property("rocksdb.is-write-stopped")? > 0
+            || property("rocksdb.actual-delayed-write-rate")? > 0
+            || property("rocksdb.estimate-pending-compaction-bytes")?
+                > max_pending_compaction_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn open() -> (TempDir, Db) {
+        let dir = TempDir::new().expect("Failed to create a temporary directory");
+        let db = Db::try_open(&dir.path().join("db")).expect("Failed to open the database");
+        (dir, db)
+    }
+
+    /// A random block and its reference.
+    fn random_block() -> ([u8; 32], Vec<u8>) {
+        let block: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+        (HashAlgorithm::ERIS.hash(&block, None), block)
+    }
+
+    #[test]
+    fn ephemeral_writes_keep_persistent_blocks() {
+        let (_dir, db) = open();
+        let (reference, block) = random_block();
+        assert!(db.write_block(reference, block.clone()).unwrap());
+        assert!(!db.write_expiring_block(reference, block, 100).unwrap());
+
+        db.collect_garbage(GcPolicy::default(), 200, false).unwrap();
+        assert!(db.read_block(reference).unwrap().is_some());
+    }
+
+    #[test]
+    fn ephemeral_writes_keep_the_later_expiry() {
+        let (_dir, db) = open();
+        let (reference, block) = random_block();
+        assert!(
+            db.write_expiring_block(reference, block.clone(), 200)
+                .unwrap()
+        );
+        assert!(!db.write_expiring_block(reference, block, 100).unwrap());
+
+        db.collect_garbage(GcPolicy::default(), 150, false).unwrap();
+        assert!(db.read_block(reference).unwrap().is_some());
+        db.collect_garbage(GcPolicy::default(), 200, false).unwrap();
+        assert!(db.read_block(reference).unwrap().is_none());
+    }
+
+    #[test]
+    fn max_block_age_keeps_pinned_blocks() {
+        let (_dir, db) = open();
+        let (pinned, block) = random_block();
+        db.write_block(pinned, block).unwrap();
+        let (cached, block) = random_block();
+        db.cache_block(cached, block).unwrap();
+
+        let policy = GcPolicy {
+            max_block_age: Some(1),
+            max_store_bytes: None,
+        };
+        let reclaimed = db
+            .collect_garbage(policy, utils::unix_time() + 10, false)
+            .unwrap();
+
+        assert_eq!(reclaimed.blocks, 1);
+        assert!(db.read_block(pinned).unwrap().is_some());
+        assert!(db.read_block(cached).unwrap().is_none());
+    }
+
+    /// Access time recorded for a block.
+    fn accessed(db: &Db, reference: [u8; 32]) -> u64 {
+        let entry = db
+            .inner
+            .get_cf(db.access().unwrap(), reference)
+            .unwrap()
+            .unwrap();
+        u64::from_be_bytes(entry[..8].try_into().unwrap())
+    }
+
+    /// Set the access time recorded for a block.
+    fn set_accessed(db: &Db, reference: [u8; 32], accessed: u64, length: usize) {
+        let mut entry = [0u8; 16];
+        entry[..8].copy_from_slice(&accessed.to_be_bytes());
+        entry[8..].copy_from_slice(&(length as u64).to_be_bytes());
+        db.inner
+            .put_cf(db.access().unwrap(), reference, entry)
+            .unwrap();
+    }
+
+    #[test]
+    fn reads_update_stale_access_times_only() {
+        let (_dir, db) = open();
+        let (reference, block) = random_block();
+        let length = block.len();
+        db.write_block(reference, block).unwrap();
+        let now = utils::unix_time();
+
+        set_accessed(&db, reference, now - 10, length);
+        db.read_block(reference).unwrap();
+        assert_eq!(accessed(&db, reference), now - 10);
+
+        set_accessed(&db, reference, now - 2 * ACCESS_RESOLUTION, length);
+        db.read_block(reference).unwrap();
+        assert!(accessed(&db, reference) >= now);
+    }
+
+    #[test]
+    fn iterates_blocks_without_metadata() {
+        let (_dir, db) = open();
+        let blocks: Vec<_> = (0..3).map(|_| random_block()).collect();
+        db.write_block(blocks[0].0, blocks[0].1.clone()).unwrap();
+        db.cache_block(blocks[1].0, blocks[1].1.clone()).unwrap();
+        db.write_expiring_block(blocks[2].0, blocks[2].1.clone(), u64::MAX)
+            .unwrap();
+        let root = blocks[0].0;
+        db.add_owner(root, &[blocks[0].0, blocks[1].0]).unwrap();
+        db.cache_inline(root, &[1; 32], b"content", u64::MAX)
+            .unwrap();
+        db.claim_extension(root).unwrap();
+
+        let references: HashSet<_> = db.iter_references().map(Result::unwrap).collect();
+        assert_eq!(
+            references,
+            blocks.iter().map(|(reference, _)| *reference).collect()
+        );
+        let mut stored: Vec<_> = db.iter_blocks().map(Result::unwrap).collect();
+        stored.sort();
+        let mut expected = blocks;
+        expected.sort();
+        assert_eq!(stored, expected);
+    }
+
+    #[test]
+    fn corrupt_blocks_are_rejected_and_replaced() {
+        let (_dir, db) = open();
+        let (reference, block) = random_block();
+        db.write_block(reference, block.clone()).unwrap();
+        let mut corrupt = block.clone();
+        corrupt[0] ^= 1;
+        db.inner.put(reference, corrupt).unwrap();
+
+        assert_eq!(db.read_verified_block(reference).unwrap(), None);
+        assert!(db.cache_block(reference, block.clone()).unwrap());
+        assert_eq!(
+            db.read_verified_block(reference).unwrap(),
+            Some(block.clone())
+        );
+        // Replacing the block keeps its pin, and an intact one is kept as is
+        assert_eq!(db.pin_count(reference).unwrap(), 1);
+        assert!(!db.cache_block(reference, block).unwrap());
     }
 }
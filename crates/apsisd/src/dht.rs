@@ -0,0 +1,197 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use arc_swap::ArcSwap;
+use mainline::{Dht, Id};
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+
+use crate::error::{ApsisErrorKind, Result};
+
+/// The DHT operations Apsis relies on. The fetch and announce paths only go
+/// through this trait, so they can run against an in-process implementation
+/// serving fixed peer sets instead of the live Mainline DHT.
+pub trait DhtClient: Send + Sync {
+    /// Whether the client has joined the DHT.
+    fn bootstrapped(&self) -> bool;
+
+    /// Peers announcing `id`, in batches as the lookup progresses.
+    fn get_peers(&self, id: Id) -> Box<dyn Iterator<Item = Vec<SocketAddrV4>> + '_>;
+
+    /// Announce this node as a peer for `id`.
+    fn announce_peer(&self, id: Id, port: Option<u16>) -> Result<Id>;
+
+    /// Whether the client still reaches other nodes. May block on a lookup.
+    fn responsive(&self) -> bool;
+}
+
+/// A DHT client shared by all requests, which can be replaced while in use
+/// when it stops responding.
+#[derive(Clone)]
+pub struct SharedDht(Arc<ArcSwap<Box<dyn DhtClient>>>);
+
+impl SharedDht {
+    pub fn new(dht: impl DhtClient + 'static) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(Box::new(dht))))
+    }
+
+    /// The current client. Operations started on it finish on it, even if it
+    /// is replaced in the meantime.
+    pub fn current(&self) -> Arc<Box<dyn DhtClient>> {
+        self.0.load_full()
+    }
+
+    pub fn replace(&self, dht: impl DhtClient + 'static) {
+        self.0.store(Arc::new(Box::new(dht)));
+    }
+}
+
+impl DhtClient for Dht {
+    fn bootstrapped(&self) -> bool {
+        Dht::bootstrapped(self)
+    }
+
+    fn get_peers(&self, id: Id) -> Box<dyn Iterator<Item = Vec<SocketAddrV4>> + '_> {
+        Box::new(Dht::get_peers(self, id))
+    }
+
+    fn announce_peer(&self, id: Id, port: Option<u16>) -> Result<Id> {
+        Dht::announce_peer(self, id, port)
+            .map_err(|err| ApsisErrorKind::Announce(err.to_string()).into())
+    }
+
+    fn responsive(&self) -> bool {
+        // A lookup of a random ID finds some nodes on any live DHT
+        !self.find_node(Id::random()).is_empty()
+    }
+}
+
+/// An in-process DHT for tests, finding the peers added to it or announced
+/// through it.
+#[cfg(test)]
+pub mod mock {
+    use mainline::Id;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::{Arc, Mutex, PoisonError};
+
+    use super::DhtClient;
+    use crate::error::Result;
+
+    /// Peers by ID, shared by every clone so that tests can inspect a client
+    /// handed to a node.
+    #[derive(Clone)]
+    pub struct MockDht {
+        bootstrapped: bool,
+        peers: Arc<Mutex<Vec<(Id, SocketAddrV4)>>>,
+    }
+
+    impl Default for MockDht {
+        fn default() -> Self {
+            Self {
+                bootstrapped: true,
+                peers: Arc::default(),
+            }
+        }
+    }
+
+    impl MockDht {
+        /// A client that never bootstraps, as when the node is offline.
+        pub fn unbootstrapped() -> Self {
+            Self {
+                bootstrapped: false,
+                ..Self::default()
+            }
+        }
+
+        /// Make `peer` one of the peers found for `id`.
+        pub fn add_peer(&self, id: Id, peer: SocketAddrV4) {
+            self.peers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push((id, peer));
+        }
+
+        /// Peers found for `id`, in the order they were added.
+        pub fn peers(&self, id: Id) -> Vec<SocketAddrV4> {
+            self.peers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .filter(|(peer_id, _)| *peer_id == id)
+                .map(|(_, peer)| *peer)
+                .collect()
+        }
+    }
+
+    impl DhtClient for MockDht {
+        fn bootstrapped(&self) -> bool {
+            self.bootstrapped
+        }
+
+        fn get_peers(&self, id: Id) -> Box<dyn Iterator<Item = Vec<SocketAddrV4>> + '_> {
+            let peers = self.peers(id);
+            Box::new((!peers.is_empty()).then_some(peers).into_iter())
+        }
+
+        fn announce_peer(&self, id: Id, port: Option<u16>) -> Result<Id> {
+            // Without a port, peers would be told the port of the DHT socket
+            let port = port.unwrap_or_default();
+            self.add_peer(id, SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+            Ok(id)
+        }
+
+        fn responsive(&self) -> bool {
+            self.bootstrapped
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockDht;
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn announced_peers_are_found() {
+        let dht = SharedDht::new(MockDht::default());
+        let id = Id::random();
+        dht.current().announce_peer(id, Some(8000)).unwrap();
+
+        let found: Vec<_> = dht.current().get_peers(id).flatten().collect();
+        assert_eq!(found, [SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8000)]);
+        assert_eq!(dht.current().get_peers(Id::random()).count(), 0);
+    }
+
+    #[test]
+    fn replaced_client_serves_later_lookups() {
+        let dht = SharedDht::new(MockDht::unbootstrapped());
+        let before = dht.current();
+        let replacement = MockDht::default();
+        let id = Id::random();
+        let peer = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8000);
+        replacement.add_peer(id, peer);
+        dht.replace(replacement);
+
+        assert!(!before.bootstrapped());
+        assert_eq!(before.get_peers(id).count(), 0);
+        assert!(dht.current().bootstrapped());
+        assert_eq!(
+            dht.current().get_peers(id).flatten().collect::<Vec<_>>(),
+            [peer]
+        );
+    }
+}
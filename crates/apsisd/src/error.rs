@@ -27,10 +27,18 @@ use thiserror_ext::Box;
 #[derive(Debug, Error, Box)]
 #[thiserror_ext(newtype(name = ApsisError))]
 pub enum ApsisErrorKind {
+    #[error("Announce error: `{0}`")]
+    Announce(String),
     #[error("Block not found: `{0}`")]
     BlockNotFound(String),
-    #[error("Directory error: `{0}`")]
-    Directory(String),
+    #[error("Configuration error: `{0}`")]
+    Config(String),
+    #[error("Database error: `{0}`")]
+    Database(String),
+    #[error("DHT not ready: `{0}`")]
+    DhtNotReady(String),
+    #[error("Encode error: `{0}`")]
+    Encode(String),
     #[error("Figment error: `{0}`")]
     Figment(#[from] figment::Error),
     #[error("Mainline ID error: `{0}`")]
@@ -43,8 +51,12 @@ pub enum ApsisErrorKind {
     Reqwest(#[from] ReqwestError),
     #[error("RocksDB error: `{0}`")]
     RocksDB(#[from] RocksDBError),
+    #[error("Upload exceeds the maximum of {0} blocks")]
+    TooManyBlocks(usize),
     #[error("TryFromSliceError: `{0}`")]
     TryFromSliceError(#[from] TryFromSliceError),
+    #[error("Verification error: `{0}`")]
+    Verify(String),
 }
 
 pub type Result<T> = std::result::Result<T, ApsisError>;
@@ -15,21 +15,35 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 mod api;
+mod chain;
+mod conditional;
 mod db;
+mod dht;
+mod drain;
 mod error;
+mod manifest;
+mod metrics;
+mod prefetch;
+mod ratelimit;
+#[cfg(test)]
+mod testing;
+mod tree;
+mod upload;
 mod utils;
+mod webhook;
 
 use axum::{
     Router,
     extract::{Request, State},
-    http::{StatusCode, header},
+    http::{HeaderName, Method, StatusCode, header},
     middleware::{self, Next},
-    response::Response,
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
 use clap::Parser;
 use clap_verbosity_flag::Verbosity;
 use directories::ProjectDirs;
+use eris_rs::types::ReadCapability;
 use error::{ApsisErrorKind, Result};
 use figment::{
     Figment,
@@ -42,17 +56,48 @@ use opentelemetry_sdk::{metrics::SdkMeterProvider, trace::SdkTracer};
 use rand::SeedableRng;
 use rand_chacha::ChaCha20Rng;
 use serde::{Deserialize, Serialize};
+use std::any::Any;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use subtle::ConstantTimeEq;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Semaphore;
 use tokio_util::task::TaskTracker;
-use tracing::debug;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tracing::{Instrument, debug, error, info, info_span, warn};
 use tracing_log::AsTrace;
 use tracing_opentelemetry::MetricsLayer;
 use tracing_subscriber::prelude::*;
 
-use api::ApiState;
+use api::{ApiState, DefaultAccept, FetchPriority};
+use db::GcPolicy;
+use dht::SharedDht;
+use drain::Drain;
+use metrics::Failure;
+use ratelimit::RateLimit;
+use upload::InFlight;
+use webhook::{Webhook, Webhooks};
+
+/// How often expired or old blocks are removed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the DHT client is checked for responsiveness.
+const DHT_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often a drain checks whether requests and background tasks are done.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Configuration directory used when there is no home directory to find one in.
+const HEADLESS_CONFIG_DIR: &str = "/etc/apsis";
+
+/// Data directory used when there is no home directory to find one in.
+const HEADLESS_DATA_DIR: &str = "/var/lib/apsis";
+
+/// Header carrying the ID assigned to each request.
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
 
 /// Apsis is a global Content-Addressed Store for the open web.
 #[derive(Debug, Parser, Serialize, Deserialize)]
@@ -62,12 +107,17 @@ struct Cli {
     #[command(flatten)]
     verbose: Verbosity,
 
+    /// Path to configuration file
+    #[arg(short, long)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
     /// IP address and port to bind to
     #[arg(short, long)]
     #[serde(skip_serializing_if = "::std::option::Option::is_none")]
     bind: Option<String>,
 
-    /// Port to advertise (otherwise uses bind port)
+    /// Port to advertise (otherwise uses bind port, or announces nothing when bound to a Unix socket)
     #[arg(short, long)]
     #[serde(skip_serializing_if = "::std::option::Option::is_none")]
     port: Option<u16>,
@@ -85,6 +135,142 @@ struct Cli {
     /// Enable Opentelemetry
     #[arg(short, long)]
     opentelemetry: bool,
+
+    /// Fail to start if Opentelemetry can't be set up
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::ops::Not::not")]
+    telemetry_required: bool,
+
+    /// Number of block storage workers (0 stores blocks inline)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    write_workers: Option<usize>,
+
+    /// Maximum number of blocks queued for the storage workers
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    write_queue: Option<usize>,
+
+    /// Maximum number of chunks of a file upload buffered ahead of the encoder
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    upload_buffer: Option<usize>,
+
+    /// Maximum number of block announcements in flight across all uploads
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_announcements: Option<usize>,
+
+    /// Maximum number of blocks a single upload may produce
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_blocks: Option<usize>,
+
+    /// Pending compaction bytes above which uploads are refused
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_pending_compaction_bytes: Option<u64>,
+
+    /// Representation to serve when the Accept header matches no known type
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    default_accept: Option<DefaultAccept>,
+
+    /// Secret used to sign webhook notifications, required with webhooks
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    webhook_secret: Option<String>,
+
+    /// Seconds to keep the blocks of ephemeral uploads
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    ephemeral_ttl: Option<u64>,
+
+    /// Announce blocks only once their upload has completed
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::ops::Not::not")]
+    defer_announce: bool,
+
+    /// Enable authenticated debugging endpoints
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::ops::Not::not")]
+    debug_endpoints: bool,
+
+    /// Seconds after which unpinned blocks are evicted
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_block_age: Option<u64>,
+
+    /// Bytes of blocks above which the least recently used are evicted
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_store_bytes: Option<u64>,
+
+    /// Sources consulted for blocks when reading, in order
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    fetch_priority: Option<FetchPriority>,
+
+    /// Log full capabilities, including their keys, rather than only their root reference
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::ops::Not::not")]
+    log_capabilities: bool,
+
+    /// ERIS URN of the content to serve at the root path
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    root_capability: Option<String>,
+
+    /// Keep and announce blocks fetched from the DHT while serving reads (default true)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    pin_on_fetch: Option<bool>,
+
+    /// Hex Ed25519 public key that signed manifests must be signed with
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    manifest_key: Option<String>,
+
+    /// Authorization token for the /admin endpoints, disabled without one
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    admin_auth: Option<String>,
+
+    /// Maximum number of /admin requests per minute
+    #[arg(long, value_name = "N")]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    admin_rate_limit: Option<u32>,
+
+    /// Seconds a read may spend fetching blocks from the DHT (default no limit)
+    #[arg(long, value_name = "SECONDS")]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    read_timeout: Option<u64>,
+
+    /// Also keep the content of uploads up to this many bytes decoded, for reads
+    /// served in a single lookup (default disabled)
+    #[arg(long, value_name = "BYTES")]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    inline_max_bytes: Option<u64>,
+
+    /// Maximum bytes of content kept decoded for reads
+    #[arg(long, value_name = "BYTES")]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    inline_cache_bytes: Option<u64>,
+
+    /// Answer reads of bare block references with 400, serving only full capabilities
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::ops::Not::not")]
+    strict_capabilities: bool,
+
+    /// Check blocks read from the database against their reference (default true)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    verify_local_reads: Option<bool>,
+
+    /// Number of blocks fetched concurrently ahead of a streamed download, 0 to disable (default 0)
+    #[arg(long, value_name = "N")]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    prefetch_blocks: Option<usize>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -95,17 +281,174 @@ struct Config {
     /// IP address and port to bind to
     bind: String,
 
-    /// Port to advertise (otherwise uses bind port)
+    /// Port to advertise (otherwise uses bind port, or announces nothing when
+    /// bound to a Unix socket)
     port: Option<u16>,
 
     /// API authorization token
     auth: String,
 
-    /// Path to Oxigraph database file
+    /// Path to Rocksdb database file (defaults to `db` in the data directory)
     database: String,
 
     /// Enable Opentelemetry
     opentelemetry: bool,
+
+    /// Fail to start if Opentelemetry can't be set up
+    #[serde(default)]
+    telemetry_required: bool,
+
+    /// Number of block storage workers (0 stores blocks inline)
+    #[serde(default)]
+    write_workers: usize,
+
+    /// Maximum number of blocks queued for the storage workers
+    #[serde(default = "default_write_queue")]
+    write_queue: usize,
+
+    /// Maximum number of chunks of a file upload buffered ahead of the encoder
+    #[serde(default = "default_upload_buffer")]
+    upload_buffer: usize,
+
+    /// Maximum number of block announcements in flight across all uploads
+    #[serde(default = "default_max_announcements")]
+    max_announcements: usize,
+
+    /// Maximum number of blocks a single upload may produce
+    #[serde(default = "default_max_blocks")]
+    max_blocks: usize,
+
+    /// Pending compaction bytes above which uploads are refused
+    #[serde(default = "default_max_pending_compaction_bytes")]
+    max_pending_compaction_bytes: u64,
+
+    /// Representation to serve when the Accept header matches no known type
+    default_accept: Option<DefaultAccept>,
+
+    /// Webhooks notified of uploads
+    #[serde(default)]
+    webhooks: Vec<Webhook>,
+
+    /// Secret used to sign webhook notifications, required with webhooks
+    webhook_secret: Option<String>,
+
+    /// Seconds to keep the blocks of ephemeral uploads
+    #[serde(default = "default_ephemeral_ttl")]
+    ephemeral_ttl: u64,
+
+    /// Announce blocks only once their upload has completed
+    #[serde(default)]
+    defer_announce: bool,
+
+    /// Enable authenticated debugging endpoints
+    #[serde(default)]
+    debug_endpoints: bool,
+
+    /// Seconds after which unpinned blocks are evicted
+    max_block_age: Option<u64>,
+
+    /// Bytes of blocks above which the least recently used are evicted
+    max_store_bytes: Option<u64>,
+
+    /// Sources consulted for blocks when reading, in order
+    #[serde(default)]
+    fetch_priority: FetchPriority,
+
+    /// Log full capabilities, including their keys, rather than only their root reference
+    #[serde(default)]
+    log_capabilities: bool,
+
+    /// ERIS URN of the content to serve at the root path
+    root_capability: Option<String>,
+
+    /// Keep and announce blocks fetched from the DHT while serving reads
+    #[serde(default = "default_pin_on_fetch")]
+    pin_on_fetch: bool,
+
+    /// Hex Ed25519 public key that signed manifests must be signed with
+    manifest_key: Option<String>,
+
+    /// Authorization token for the /admin endpoints, disabled without one
+    admin_auth: Option<String>,
+
+    /// Maximum number of /admin requests per minute
+    #[serde(default = "default_admin_rate_limit")]
+    admin_rate_limit: u32,
+
+    /// Seconds a read may spend fetching blocks from the DHT
+    read_timeout: Option<u64>,
+
+    /// Also keep the content of uploads up to this many bytes decoded, for reads
+    /// served in a single lookup
+    inline_max_bytes: Option<u64>,
+
+    /// Maximum bytes of content kept decoded for reads
+    #[serde(default = "default_inline_cache_bytes")]
+    inline_cache_bytes: u64,
+
+    /// Answer reads of bare block references with 400, serving only full capabilities
+    #[serde(default)]
+    strict_capabilities: bool,
+
+    /// Check blocks read from the database against their reference
+    #[serde(default = "default_verify_local_reads")]
+    verify_local_reads: bool,
+
+    /// Number of blocks fetched ahead of a streamed download, 0 to disable
+    #[serde(default = "default_prefetch_blocks")]
+    prefetch_blocks: usize,
+}
+
+fn default_write_queue() -> usize {
+    64
+}
+
+fn default_upload_buffer() -> usize {
+    16
+}
+
+fn default_max_announcements() -> usize {
+    64
+}
+
+fn default_max_blocks() -> usize {
+    // 4 GiB of 1 KiB blocks, or 128 GiB of 32 KiB blocks
+    4 * 1024 * 1024
+}
+
+fn default_max_pending_compaction_bytes() -> u64 {
+    // Half of RocksDB's default soft limit, so clients back off before stalls
+    32 * 1024 * 1024 * 1024
+}
+
+fn default_ephemeral_ttl() -> u64 {
+    60 * 60
+}
+
+fn default_pin_on_fetch() -> bool {
+    true
+}
+
+fn default_admin_rate_limit() -> u32 {
+    60
+}
+
+fn default_inline_cache_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_verify_local_reads() -> bool {
+    true
+}
+
+fn default_prefetch_blocks() -> usize {
+    0
+}
+
+/// Port to announce blocks on: `port` if set, otherwise the port of `bind`.
+/// Peers fetch blocks on the announced port, which a Unix socket lacks.
+fn announce_port(port: Option<u16>, bind: &str) -> Option<u16> {
+    port.or_else(|| bind.parse::<SocketAddr>().ok().map(|addr| addr.port()))
 }
 
 async fn authenticate(
@@ -113,10 +456,11 @@ async fn authenticate(
     req: Request,
     next: Next,
 ) -> std::result::Result<Response, StatusCode> {
-    //Only the content endpoint is authenticated
-    if !(req.uri() == "/uri-res/R2N" || req.uri() == "/uri-res/R2N/") {
+    // OPTIONS only describes the route
+    if req.method() == Method::OPTIONS {
         return Ok(next.run(req).await);
     }
+
     let auth_header = req
         .headers()
         .get(header::AUTHORIZATION)
@@ -130,6 +474,118 @@ async fn authenticate(
     }
 }
 
+/// Authenticate requests to the /admin endpoints with the admin token, then
+/// rate limit them. The API token alone is refused with a 403.
+async fn authenticate_admin(State(state): State<ApiState>, req: Request, next: Next) -> Response {
+    // OPTIONS only describes the route
+    if req.method() == Method::OPTIONS {
+        return next.run(req).await;
+    }
+
+    let auth_header = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok());
+    let matches = |token: &str| {
+        auth_header.is_some_and(|auth_header| auth_header.as_bytes().ct_eq(token.as_bytes()).into())
+    };
+    if !state.admin_auth.as_deref().is_some_and(matches) {
+        return if matches(&state.auth) {
+            StatusCode::FORBIDDEN.into_response()
+        } else {
+            StatusCode::UNAUTHORIZED.into_response()
+        };
+    }
+
+    if let Err(wait) = state.admin_limit.check() {
+        Failure::RateLimited.record();
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait.as_secs().max(1).to_string())],
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// Run each request in a span carrying its request ID, method, path and query,
+/// so that anything logged while handling it, including panics, can be traced
+/// back to the request, and log its status once handled. Unless
+/// `log_capabilities` is set, capabilities in the query are redacted to their
+/// root reference so that logs don't collect decryption keys.
+async fn request_span(State(log_capabilities): State<bool>, req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let query = match req.uri().query() {
+        Some(query) if log_capabilities => query.to_owned(),
+        Some(query) => utils::redact_urn(query),
+        None => String::new(),
+    };
+    let span = info_span!("request", id = %id, %method, %path, %query);
+    async move {
+        let response = next.run(req).await;
+        info!(status = response.status().as_u16(), "Handled request");
+        response
+    }
+    .instrument(span)
+    .await
+}
+
+/// Count requests in flight until their response is ready, to report the
+/// progress of a drain.
+async fn count_requests(State(drain): State<Drain>, req: Request, next: Next) -> Response {
+    let _request = drain.request();
+    next.run(req).await
+}
+
+/// Wait for Ctrl-C or SIGTERM, then drain: keep serving, with `/readyz`
+/// failing so that load balancers stop routing here, until no requests or
+/// background tasks are left.
+async fn drain_on_signal(drain: Drain, tracker: TaskTracker) {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    drain.begin();
+    info!("Draining before shutdown");
+    while drain.requests() > 0 || !tracker.is_empty() {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    tracker.close();
+    info!("Drained, shutting down");
+}
+
+/// Turn a handler panic into a 500 response, logging and counting it.
+fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+    let message = if let Some(message) = err.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = err.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown panic payload"
+    };
+    error!("Handler panicked: {}", message);
+    Failure::Panic.record();
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error.").into_response()
+}
+
 fn telemetry_tracer_init() -> Result<SdkTracer> {
     let otlp_exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
 
@@ -152,27 +608,88 @@ fn telemetry_meter_init() -> Result<SdkMeterProvider> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Set project directories
-    let proj_dirs = ProjectDirs::from("tech", "throneless", "apsis").ok_or(
-        ApsisErrorKind::Directory("Failed to find project directories.".to_owned()),
-    )?;
+    // Set project directories, falling back to system-wide ones on headless
+    // systems without a home directory
+    let proj_dirs = ProjectDirs::from("tech", "throneless", "apsis");
+    let config_dir = proj_dirs.as_ref().map_or_else(
+        || PathBuf::from(HEADLESS_CONFIG_DIR),
+        |dirs| dirs.config_dir().to_owned(),
+    );
+    let data_dir = std::env::var_os("APSIS_DATA_DIR").map_or_else(
+        || {
+            proj_dirs.as_ref().map_or_else(
+                || PathBuf::from(HEADLESS_DATA_DIR),
+                |dirs| dirs.data_dir().to_owned(),
+            )
+        },
+        PathBuf::from,
+    );
 
     // Merge the configuration from CLI, environment, files, container secrets
+    let cli = Cli::parse();
+    // Only the default file may be missing
+    if let Some(path) = &cli.config
+        && !path.is_file()
+    {
+        return Err(ApsisErrorKind::Config(format!(
+            "Configuration file {} doesn't exist.",
+            path.display()
+        ))
+        .into());
+    }
+    let config = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| config_dir.join("config.toml"));
     let server: Config = Figment::new()
-        .merge(FileAdapter::wrap(Toml::file(
-            proj_dirs.config_dir().join("config.toml"),
-        )))
+        .merge(Serialized::default("database", data_dir.join("db")))
+        .merge(FileAdapter::wrap(Toml::file(config)))
         .merge(FileAdapter::wrap(Env::prefixed("APSIS_")))
-        .merge(Serialized::defaults(Cli::parse()))
+        .merge(Serialized::defaults(cli))
         .extract()?;
+    if let Some(urn) = &server.root_capability
+        && ReadCapability::from_urn(urn.clone()).is_none()
+    {
+        return Err(
+            ApsisErrorKind::Config("root_capability is not a valid ERIS URN.".to_owned()).into(),
+        );
+    }
+    let manifest_key = server
+        .manifest_key
+        .as_deref()
+        .map(manifest::parse_key)
+        .transpose()?;
+
+    // Receivers couldn't tell unsigned notifications from forged ones
+    if !server.webhooks.is_empty() && server.webhook_secret.is_none() {
+        return Err(ApsisErrorKind::Config(
+            "webhook_secret is required when webhooks are configured.".to_owned(),
+        )
+        .into());
+    }
 
-    // Setup logging and telemetry
-    if server.opentelemetry {
+    // Setup logging and telemetry. Telemetry is optional, so unless it is
+    // required, failing to set up the exporters only disables it.
+    let mut telemetry_error = None;
+    let telemetry = if server.opentelemetry {
+        match telemetry_meter_init().and_then(|meter| Ok((meter, telemetry_tracer_init()?))) {
+            Ok(telemetry) => Some(telemetry),
+            Err(err) if server.telemetry_required => return Err(err),
+            Err(err) => {
+                telemetry_error = Some(err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some((meter_provider, tracer)) = telemetry {
+        opentelemetry::global::set_meter_provider(meter_provider.clone());
         tracing_subscriber::registry()
             .with(server.verbose.log_level_filter().as_trace())
             .with(tracing_subscriber::fmt::layer())
-            .with(tracing_opentelemetry::layer().with_tracer(telemetry_tracer_init()?))
-            .with(MetricsLayer::new(telemetry_meter_init()?))
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .with(MetricsLayer::new(meter_provider))
             .init();
     } else {
         tracing_subscriber::registry()
@@ -180,33 +697,213 @@ async fn main() -> Result<()> {
             .with(tracing_subscriber::fmt::layer())
             .init();
     }
+    if let Some(err) = telemetry_error {
+        warn!(
+            "Failed to set up Opentelemetry, continuing without it: {}",
+            err
+        );
+    }
+
+    let port = announce_port(server.port, &server.bind);
+    if port.is_none() {
+        warn!(
+            "No port to advertise for a Unix socket, blocks won't be announced and ephemeral uploads are refused"
+        );
+    }
 
     // Initialize database
     let store = db::Db::try_open(&server.database.into())?;
 
-    // Initialize DHT
-    let dht = Dht::client()?;
+    // Remove expired ephemeral blocks, unpinned blocks older than max_block_age
+    // and least recently used ones above max_store_bytes in the background
+    let sweeper = store.clone();
+    let gc_policy = GcPolicy {
+        max_block_age: server.max_block_age,
+        max_store_bytes: server.max_store_bytes,
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let store = sweeper.clone();
+            match tokio::task::spawn_blocking(move || {
+                store.collect_garbage(gc_policy, utils::unix_time(), false)
+            })
+            .await
+            {
+                Ok(Ok(reclaimed)) if reclaimed.blocks > 0 => debug!(
+                    "Removed {} blocks ({} bytes)",
+                    reclaimed.blocks, reclaimed.bytes
+                ),
+                Ok(Err(err)) => warn!("Failed to collect garbage: {}", err),
+                _ => {}
+            }
+        }
+    });
+
+    // Initialize DHT, and replace the client whenever it stops responding
+    let dht = SharedDht::new(Dht::client()?);
+    let monitor = dht.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(DHT_CHECK_INTERVAL);
+        // The first tick completes immediately, while the DHT is bootstrapping
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let current = monitor.current();
+            if let Ok(true) = tokio::task::spawn_blocking(move || current.responsive()).await {
+                continue;
+            }
+            warn!("DHT is unresponsive, restarting it");
+            match Dht::client() {
+                Ok(dht) => {
+                    monitor.replace(dht);
+                    metrics::record_dht_recovery(true);
+                }
+                Err(err) => {
+                    warn!("Failed to restart DHT: {}", err);
+                    metrics::record_dht_recovery(false);
+                }
+            }
+        }
+    });
 
     // Start RNG
-    let rng = ChaCha20Rng::from_os_rng();
+    let rng = Arc::new(Mutex::new(ChaCha20Rng::from_os_rng()));
 
     // Create API state
     let tracker = TaskTracker::new();
     let state = ApiState {
+        admin_auth: server.admin_auth,
+        admin_limit: RateLimit::new(server.admin_rate_limit.max(1), Duration::from_secs(60)),
+        announce_permits: Arc::new(Semaphore::new(server.max_announcements.max(1))),
         auth: server.auth,
-        dht: Arc::new(dht),
-        port: server.port,
+        default_accept: server.default_accept,
+        defer_announce: server.defer_announce,
+        dht,
+        drain: Drain::default(),
+        ephemeral_ttl: server.ephemeral_ttl,
+        fetch_priority: server.fetch_priority,
+        gc_policy,
+        in_flight: InFlight::default(),
+        inline_cache_bytes: server.inline_cache_bytes,
+        inline_max_bytes: server.inline_max_bytes,
+        manifest_key,
+        max_blocks: server.max_blocks,
+        max_pending_compaction_bytes: server.max_pending_compaction_bytes,
+        pin_on_fetch: server.pin_on_fetch,
+        port,
+        prefetch_blocks: server.prefetch_blocks,
+        read_timeout: server.read_timeout.map(Duration::from_secs),
         rng,
+        root_capability: server.root_capability,
         store,
+        strict_capabilities: server.strict_capabilities,
         tracker: tracker.clone(),
+        upload_buffer: server.upload_buffer.max(1),
+        verify_local_reads: server.verify_local_reads,
+        webhooks: Webhooks::new(server.webhooks, server.webhook_secret),
+        write_queue: server.write_queue.max(1),
+        write_workers: server.write_workers,
     };
 
-    // Run client API
+    // Run client API. Reads are public, uploads and debugging endpoints need
+    // the API token, and /admin endpoints, only served with an admin token
+    // configured, need that token.
+    let public = Router::new()
+        .route(
+            "/uri-res/N2R",
+            get(api::name_to_resource)
+                .head(api::head_resource)
+                .options(api::describe),
+        )
+        .route("/content/log", get(api::read_log).options(api::describe))
+        .route(
+            "/content/refs",
+            get(api::capability_refs).options(api::describe),
+        );
+    let mut protected = Router::new()
+        .route(
+            "/uri-res/R2N",
+            post(api::resource_to_name).options(api::describe),
+        )
+        .route(
+            "/content/patch",
+            post(api::patch_content).options(api::describe),
+        )
+        .route(
+            "/content/append",
+            post(api::append_content).options(api::describe),
+        )
+        .route(
+            "/content/batch-stream",
+            post(api::batch_stream).options(api::describe),
+        );
+    if server.debug_endpoints {
+        protected = protected
+            .route(
+                "/debug/capability",
+                get(api::debug_capability).options(api::describe),
+            )
+            .route(
+                "/debug/blocks",
+                get(api::debug_blocks).options(api::describe),
+            )
+            .route(
+                "/debug/verify",
+                get(api::debug_verify).options(api::describe),
+            );
+    }
+    let protected =
+        protected.route_layer(middleware::from_fn_with_state(state.clone(), authenticate));
+    let mut routes = public.merge(protected);
+    if state.admin_auth.is_some() {
+        let admin = Router::new()
+            .route(
+                "/admin/block/{reference}",
+                get(api::admin_block).options(api::describe),
+            )
+            .route(
+                "/admin/block/{reference}/capabilities",
+                get(api::block_capabilities).options(api::describe),
+            )
+            .route(
+                "/admin/gc",
+                post(api::collect_garbage).options(api::describe),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                authenticate_admin,
+            ));
+        routes = routes.merge(admin);
+    } else {
+        info!("No admin_auth set, /admin endpoints are disabled");
+    }
+    let drain = state.drain.clone();
     let app = Router::new()
-        .route("/uri-res/N2R", get(api::name_to_resource))
-        .route("/uri-res/R2N", post(api::resource_to_name))
-        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
-        .with_state(state);
+        .route("/", get(api::root).options(api::describe))
+        .route("/version", get(api::version).options(api::describe))
+        .nest(api::API_PREFIX, routes.clone())
+        .merge(routes)
+        .layer(middleware::from_fn_with_state(
+            drain.clone(),
+            count_requests,
+        ))
+        // Not counted as in flight, so that polling them doesn't hold up a drain
+        .route("/readyz", get(api::readyz).options(api::describe))
+        .route(
+            "/drain-status",
+            get(api::drain_status).options(api::describe),
+        )
+        .method_not_allowed_fallback(api::method_not_allowed)
+        .with_state(state)
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(middleware::from_fn_with_state(
+            server.log_capabilities,
+            request_span,
+        ))
+        .layer(PropagateRequestIdLayer::new(REQUEST_ID_HEADER))
+        .layer(SetRequestIdLayer::new(REQUEST_ID_HEADER, MakeRequestUuid));
 
     println!("Server is running 🤖");
 
@@ -218,16 +915,30 @@ async fn main() -> Result<()> {
             listener,
             app.into_make_service_with_connect_info::<SocketAddr>(),
         )
-        .with_graceful_shutdown(async move { tracker.wait().await })
+        .with_graceful_shutdown(drain_on_signal(drain, tracker))
         .await?;
     } else {
         let Ok(path) = server.bind.parse::<PathBuf>();
         let _ = tokio::fs::remove_file(&path).await;
         let listener = tokio::net::UnixListener::bind(path).expect("Unable to bind to address");
         axum::serve(listener, app.into_make_service())
-            .with_graceful_shutdown(async move { tracker.wait().await })
+            .with_graceful_shutdown(drain_on_signal(drain, tracker))
             .await?;
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_port_prefers_the_configured_port() {
+        assert_eq!(announce_port(None, "127.0.0.1:8000"), Some(8000));
+        assert_eq!(announce_port(None, "[::1]:8080"), Some(8080));
+        assert_eq!(announce_port(Some(443), "127.0.0.1:8000"), Some(443));
+        assert_eq!(announce_port(Some(443), "/run/apsis.sock"), Some(443));
+        assert_eq!(announce_port(None, "/run/apsis.sock"), None);
+    }
+}
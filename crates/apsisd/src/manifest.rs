@@ -0,0 +1,67 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Signed directory manifests. A manifest maps paths to URNs, and a signed
+//! one wraps those entries with an Ed25519 signature by the publisher:
+//!
+//! ```json
+//! {"entries": {"index.html": "urn:eris:..."}, "signature": "<hex>"}
+//! ```
+//!
+//! The signature covers the entries serialized as compact JSON with their
+//! keys sorted, so that it doesn't depend on how the document was formatted.
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::error::{ApsisErrorKind, Result};
+
+/// Parse a hex encoded Ed25519 public key.
+pub fn parse_key(key: &str) -> Result<VerifyingKey> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(key, &mut bytes)
+        .ok()
+        .and_then(|_| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| {
+            ApsisErrorKind::Config("manifest_key is not a hex Ed25519 public key.".to_owned())
+                .into()
+        })
+}
+
+/// Check a JSON document's signature against `key`, returning `None` if it
+/// isn't a signed manifest at all.
+pub fn verify(json: &Value, key: &VerifyingKey) -> Option<bool> {
+    let Value::Object(object) = json else {
+        return None;
+    };
+    let (Some(entries), Some(Value::String(signature)), 2) =
+        (object.get("entries"), object.get("signature"), object.len())
+    else {
+        return None;
+    };
+    let Ok(entries) = serde_json::from_value::<BTreeMap<String, String>>(entries.clone()) else {
+        return Some(false);
+    };
+    let mut bytes = [0u8; 64];
+    if hex::decode_to_slice(signature, &mut bytes).is_err() {
+        return Some(false);
+    }
+    let Ok(message) = serde_json::to_vec(&entries) else {
+        return Some(false);
+    };
+    Some(key.verify(&message, &Signature::from_bytes(&bytes)).is_ok())
+}
@@ -0,0 +1,111 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use opentelemetry::{
+    KeyValue, global,
+    metrics::{Counter, UpDownCounter},
+};
+use std::sync::LazyLock;
+
+static FAILURES: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("apsis")
+        .u64_counter("apsis.failures")
+        .with_description("Failed requests, labelled by cause")
+        .build()
+});
+
+static DHT_RECOVERIES: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("apsis")
+        .u64_counter("apsis.dht.recoveries")
+        .with_description("Attempts to replace an unresponsive DHT client, labelled by outcome")
+        .build()
+});
+
+/// Count an attempt to replace an unresponsive DHT client.
+pub fn record_dht_recovery(replaced: bool) {
+    let outcome = if replaced { "replaced" } else { "failed" };
+    DHT_RECOVERIES.add(1, &[KeyValue::new("outcome", outcome)]);
+}
+
+static ANNOUNCEMENTS_QUEUED: LazyLock<UpDownCounter<i64>> = LazyLock::new(|| {
+    global::meter("apsis")
+        .i64_up_down_counter("apsis.announcements.queued")
+        .with_description("Block announcements waiting for a free announcement slot")
+        .build()
+});
+
+/// An announcement counted as queued until dropped.
+pub struct QueuedAnnouncement(());
+
+impl QueuedAnnouncement {
+    pub fn start() -> Self {
+        ANNOUNCEMENTS_QUEUED.add(1, &[]);
+        Self(())
+    }
+}
+
+impl Drop for QueuedAnnouncement {
+    fn drop(&mut self) {
+        ANNOUNCEMENTS_QUEUED.add(-1, &[]);
+    }
+}
+
+/// Cause of a failed upload or download, used as the `cause` metric label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    BadSignature,
+    BlockNotFound,
+    DecodeFailure,
+    DhtNotReady,
+    EncodeFailure,
+    IntegrityFailure,
+    InvalidCapability,
+    InvalidUpload,
+    NotJson,
+    Overloaded,
+    Panic,
+    RateLimited,
+    TooManyBlocks,
+    UnsupportedMedia,
+    VerifyFailure,
+}
+
+impl Failure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::BadSignature => "bad_signature",
+            Self::BlockNotFound => "block_not_found",
+            Self::DecodeFailure => "decode_failure",
+            Self::DhtNotReady => "dht_not_ready",
+            Self::EncodeFailure => "encode_failure",
+            Self::IntegrityFailure => "integrity_failure",
+            Self::InvalidCapability => "invalid_capability",
+            Self::InvalidUpload => "invalid_upload",
+            Self::NotJson => "not_json",
+            Self::Overloaded => "overloaded",
+            Self::Panic => "panic",
+            Self::RateLimited => "rate_limited",
+            Self::TooManyBlocks => "too_many_blocks",
+            Self::UnsupportedMedia => "unsupported_media",
+            Self::VerifyFailure => "verify_failure",
+        }
+    }
+
+    /// Increment the failure counter for this cause.
+    pub fn record(self) {
+        FAILURES.add(1, &[KeyValue::new("cause", self.as_str())]);
+    }
+}
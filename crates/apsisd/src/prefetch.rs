@@ -0,0 +1,359 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Fetching the blocks of a capability ahead of a sequential decode, so that
+//! the latency of fetching from peers overlaps with serving earlier blocks.
+
+use eris_rs::types::{BlockStorageError, ReadCapability, Reference};
+use std::collections::HashMap;
+use std::io;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use tokio::runtime::Handle;
+
+use crate::tree::Tree;
+use crate::utils;
+
+/// A block in the window, fetched or being fetched.
+struct Slot {
+    /// Position of the block in the walk of the tree.
+    index: u64,
+    block: Option<Result<Vec<u8>, BlockStorageError>>,
+    /// Whether the reader took the block while the walk may still read it as
+    /// a node.
+    taken: bool,
+}
+
+#[derive(Default)]
+struct Window {
+    slots: HashMap<Reference, Slot>,
+    visited: u64,
+    /// Block the walk visited last, which it reads next if it is a node.
+    last: Option<Reference>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    window: Mutex<Window>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Window> {
+        self.window.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait until the block of `reference` is fetched, unless it isn't in the
+    /// window or the reader is gone.
+    fn ready<'a>(
+        &self,
+        mut window: MutexGuard<'a, Window>,
+        reference: Reference,
+    ) -> MutexGuard<'a, Window> {
+        while !window.closed
+            && window
+                .slots
+                .get(&reference)
+                .is_some_and(|slot| slot.block.is_none())
+        {
+            window = self
+                .changed
+                .wait(window)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        window
+    }
+
+    /// Add the block the walk visits to the window once there is room for it.
+    /// Returns whether it should be fetched, or `None` once the reader is gone.
+    fn visit(&self, reference: Reference, lookahead: usize) -> Option<bool> {
+        let mut window = self.lock();
+        if let Some(last) = window.last.take()
+            && window.slots.get(&last).is_some_and(|slot| slot.taken)
+        {
+            window.slots.remove(&last);
+        }
+        while !window.closed && window.slots.len() >= lookahead {
+            window = self
+                .changed
+                .wait(window)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if window.closed {
+            return None;
+        }
+        window.last = Some(reference);
+        if window.slots.contains_key(&reference) {
+            return Some(false);
+        }
+        let index = window.visited;
+        window.visited += 1;
+        window.slots.insert(
+            reference,
+            Slot {
+                index,
+                block: None,
+                taken: false,
+            },
+        );
+        Some(true)
+    }
+
+    fn fetched(&self, reference: Reference, block: Result<Vec<u8>, BlockStorageError>) {
+        if let Some(slot) = self.lock().slots.get_mut(&reference) {
+            slot.block = Some(block);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Take a block for the reader, dropping those before it in the walk,
+    /// which the decoder skipped.
+    fn take(&self, reference: Reference) -> Option<Vec<u8>> {
+        let mut window = self.ready(self.lock(), reference);
+        let last = window.last == Some(reference);
+        let slot = window.slots.get_mut(&reference)?;
+        let index = slot.index;
+        let block = if last {
+            slot.taken = true;
+            slot.block.as_ref()?.as_ref().ok().cloned()
+        } else {
+            window.slots.remove(&reference)?.block?.ok()
+        };
+        window.slots.retain(|_, slot| slot.index >= index);
+        self.changed.notify_all();
+        block
+    }
+
+    /// Read a node for the walk, leaving it in the window for the reader
+    /// unless the reader already took it.
+    fn node(&self, reference: Reference) -> Option<Vec<u8>> {
+        let mut window = self.ready(self.lock(), reference);
+        let slot = window.slots.get(&reference)?;
+        let block = slot.block.as_ref()?.as_ref().ok()?.clone();
+        if slot.taken {
+            window.slots.remove(&reference);
+            self.changed.notify_all();
+        }
+        Some(block)
+    }
+}
+
+/// A block being fetched, recorded as failed if the fetch ends without a
+/// result, so that a panicking fetch doesn't leave the reader waiting.
+struct Fetching {
+    shared: Arc<Shared>,
+    reference: Reference,
+}
+
+impl Drop for Fetching {
+    fn drop(&mut self) {
+        let mut window = self.shared.lock();
+        if let Some(slot) = window.slots.get_mut(&self.reference)
+            && slot.block.is_none()
+        {
+            slot.block = Some(Err(io::Error::other("Block fetch failed.")));
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+/// Blocks of a capability fetched concurrently on the blocking pool, at most
+/// `lookahead` blocks ahead of the reader in the order of the walk, and read
+/// back by reference.
+pub struct Prefetch {
+    shared: Arc<Shared>,
+}
+
+impl Prefetch {
+    /// Start fetching the blocks of `capability` with `read_block`. Fetching
+    /// stops once the `Prefetch` is dropped.
+    pub fn start<F>(capability: &ReadCapability, read_block: F, lookahead: usize) -> Self
+    where
+        F: Fn(Reference) -> Result<Vec<u8>, BlockStorageError> + Clone + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let capability = utils::copy_capability(capability);
+        let runtime = Handle::current();
+        let walk = shared.clone();
+        runtime.clone().spawn_blocking(move || {
+            // Nodes are read from the window, so that the walk waits for the
+            // fetch it started rather than fetching them again
+            let read_node = |reference| match walk.node(reference) {
+                Some(block) => Ok(block),
+                None => read_block(reference),
+            };
+            let _ = Tree::new(&capability, &read_node).references(&mut |reference| {
+                match walk.visit(reference, lookahead) {
+                    None => return Ok(ControlFlow::Break(())),
+                    Some(false) => {}
+                    Some(true) => {
+                        let fetching = Fetching {
+                            shared: walk.clone(),
+                            reference,
+                        };
+                        let read_block = read_block.clone();
+                        runtime.spawn_blocking(move || {
+                            let block = read_block(fetching.reference);
+                            fetching.shared.fetched(fetching.reference, block);
+                        });
+                    }
+                }
+                Ok(ControlFlow::Continue(()))
+            });
+        });
+        Self { shared }
+    }
+
+    /// Read a block, taking it from those fetched ahead when it is among
+    /// them, and with `read_block` otherwise.
+    pub fn read<F>(
+        &self,
+        reference: Reference,
+        read_block: &F,
+    ) -> Result<Vec<u8>, BlockStorageError>
+    where
+        F: Fn(Reference) -> Result<Vec<u8>, BlockStorageError>,
+    {
+        // A failed fetch is tried again, in case the failure was transient
+        match self.shared.take(reference) {
+            Some(block) => Ok(block),
+            None => read_block(reference),
+        }
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiState;
+    use crate::testing::TestNode;
+    use crate::upload::{self, Encoded, Options, Retention};
+    use eris_rs::{decode::decode, types::BlockSize};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::task;
+
+    thread_local! {
+        /// Whether the thread is decoding, rather than fetching ahead.
+        static READER: Cell<bool> = const { Cell::new(false) };
+    }
+
+    async fn upload(state: &ApiState, content: Vec<u8>) -> Encoded {
+        let state = state.clone();
+        let options = Options {
+            key: rand::random(),
+            convergent: false,
+            retention: Retention::Persistent,
+            verify: false,
+        };
+        task::spawn_blocking(move || {
+            upload::encode_content(&state, &mut &content[..], BlockSize::Size1KiB, &options)
+        })
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    /// Decode `capability` through a `Prefetch` of `read_block`.
+    async fn read<F>(capability: &ReadCapability, read_block: F) -> Vec<u8>
+    where
+        F: Fn(Reference) -> Result<Vec<u8>, BlockStorageError> + Clone + Send + 'static,
+    {
+        let prefetch = Prefetch::start(capability, read_block.clone(), 8);
+        let capability = utils::copy_capability(capability);
+        task::spawn_blocking(move || {
+            READER.set(true);
+            let mut buf = Vec::new();
+            decode(capability, &mut buf, &|reference| {
+                prefetch.read(reference, &read_block)
+            })
+            .unwrap();
+            buf
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fetches_each_block_once_and_concurrently() {
+        let node = TestNode::new();
+        let content: Vec<u8> = (0..64 * 1024).map(|_| rand::random()).collect();
+        let encoded = upload(&node.state, content.clone()).await;
+
+        // A slow peer, recording how often each block is fetched
+        let fetches = Arc::new(Mutex::new(HashMap::new()));
+        let (running, busiest) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let read_block = {
+            let store = node.state.store.clone();
+            let (fetches, running, busiest) = (fetches.clone(), running.clone(), busiest.clone());
+            move |reference: Reference| {
+                *fetches.lock().unwrap().entry(reference).or_insert(0) += 1;
+                busiest.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                store
+                    .read_verified_block(reference)
+                    .map_err(io::Error::other)?
+                    .ok_or_else(|| io::Error::other("Missing block."))
+            }
+        };
+        assert_eq!(read(&encoded.capability, read_block).await, content);
+
+        // The window follows the decoder, so nothing is fetched twice
+        let fetches = fetches.lock().unwrap();
+        assert_eq!(fetches.len(), encoded.blocks);
+        assert!(fetches.values().all(|count| *count == 1));
+        assert!(busiest.load(Ordering::SeqCst) > 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_past_a_panicking_fetch() {
+        let node = TestNode::new();
+        let content: Vec<u8> = (0..64 * 1024).map(|_| rand::random()).collect();
+        let encoded = upload(&node.state, content.clone()).await;
+
+        // Every fetch ahead of the reader panics, so the reader fetches each
+        // block itself once it learns that the fetch failed
+        let read_block = {
+            let store = node.state.store.clone();
+            move |reference: Reference| {
+                if !READER.get() {
+                    panic!("Fetch failed");
+                }
+                store
+                    .read_verified_block(reference)
+                    .map_err(io::Error::other)?
+                    .ok_or_else(|| io::Error::other("Missing block."))
+            }
+        };
+        let decoded = tokio::time::timeout(
+            Duration::from_secs(10),
+            read(&encoded.capability, read_block),
+        )
+        .await
+        .expect("Reader waited on a panicked fetch");
+        assert_eq!(decoded, content);
+    }
+}
@@ -0,0 +1,54 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Fixed-window request rate limiting.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Allows up to `max` requests per `window`, shared by every clone.
+#[derive(Clone)]
+pub struct RateLimit {
+    max: u32,
+    window: Duration,
+    /// Start of the current window and the requests counted in it.
+    current: Arc<Mutex<(Instant, u32)>>,
+}
+
+impl RateLimit {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            current: Arc::new(Mutex::new((Instant::now(), 0))),
+        }
+    }
+
+    /// Count a request, or return how long until the next window if the
+    /// current one is full.
+    pub fn check(&self) -> Result<(), Duration> {
+        let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if now.duration_since(current.0) >= self.window {
+            *current = (now, 0);
+        }
+        if current.1 >= self.max {
+            return Err(self.window.saturating_sub(now.duration_since(current.0)));
+        }
+        current.1 += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Nodes and peers for tests, running against an in-process DHT so that
+//! nothing reaches the network.
+
+use axum::{Router, extract::RawQuery, http::StatusCode, response::IntoResponse, routing::get};
+use eris_rs::types::Reference;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::sync::Semaphore;
+use tokio_util::task::TaskTracker;
+
+use crate::api::{ApiState, FetchPriority};
+use crate::db::{Db, GcPolicy};
+use crate::dht::SharedDht;
+use crate::dht::mock::MockDht;
+use crate::drain::Drain;
+use crate::ratelimit::RateLimit;
+use crate::upload::InFlight;
+use crate::utils;
+use crate::webhook::Webhooks;
+
+/// Port test nodes announce blocks on.
+pub const PORT: u16 = 8000;
+
+/// A node with its own database in a temporary directory, removed once the
+/// node is dropped.
+pub struct TestNode {
+    pub state: ApiState,
+    pub dht: MockDht,
+    _dir: TempDir,
+}
+
+impl TestNode {
+    pub fn new() -> Self {
+        Self::with_dht(MockDht::default())
+    }
+
+    /// A node with the defaults of `apsisd` and an empty store, looking up
+    /// and announcing blocks on `dht`.
+    pub fn with_dht(dht: MockDht) -> Self {
+        let dir = TempDir::new().expect("Unable to create a temporary directory");
+        let store = Db::try_open(&dir.path().join("db")).expect("Unable to open the database");
+        let state = ApiState {
+            admin_auth: None,
+            admin_limit: RateLimit::new(60, Duration::from_secs(60)),
+            announce_permits: Arc::new(Semaphore::new(64)),
+            auth: "secret".to_owned(),
+            default_accept: None,
+            defer_announce: false,
+            dht: SharedDht::new(dht.clone()),
+            drain: Drain::default(),
+            ephemeral_ttl: 60 * 60,
+            fetch_priority: FetchPriority::LocalFirst,
+            gc_policy: GcPolicy::default(),
+            in_flight: InFlight::default(),
+            inline_cache_bytes: 64 * 1024 * 1024,
+            inline_max_bytes: None,
+            manifest_key: None,
+            max_blocks: 4 * 1024 * 1024,
+            max_pending_compaction_bytes: u64::MAX,
+            pin_on_fetch: true,
+            port: Some(PORT),
+            prefetch_blocks: 0,
+            read_timeout: None,
+            rng: Arc::new(Mutex::new(ChaCha20Rng::from_os_rng())),
+            root_capability: None,
+            store,
+            strict_capabilities: false,
+            tracker: TaskTracker::new(),
+            upload_buffer: 16,
+            verify_local_reads: true,
+            webhooks: Webhooks::new(Vec::new(), None),
+            write_queue: 64,
+            write_workers: 0,
+        };
+        Self {
+            state,
+            dht,
+            _dir: dir,
+        }
+    }
+
+    /// Wait for background work, such as announcements, to finish.
+    pub async fn settle(&self) {
+        self.state.tracker.close();
+        self.state.tracker.wait().await;
+        self.state.tracker.reopen();
+    }
+}
+
+/// Serve `blocks` by reference on the read route the way another node would,
+/// whether or not they match their reference, and return the peer's address.
+pub async fn serve_blocks(blocks: Vec<(Reference, Vec<u8>)>) -> SocketAddrV4 {
+    let blocks: Arc<HashMap<_, _>> = Arc::new(
+        blocks
+            .into_iter()
+            .map(|(reference, block)| (utils::ref_to_urn(&reference), block))
+            .collect(),
+    );
+    let app = Router::new().route(
+        "/uri-res/N2R",
+        get(move |RawQuery(query): RawQuery| {
+            let block = query.and_then(|query| blocks.get(&query).cloned());
+            async move {
+                match block {
+                    Some(block) => block.into_response(),
+                    None => StatusCode::NOT_FOUND.into_response(),
+                }
+            }
+        }),
+    );
+    serve(app).await
+}
+
+/// Serve `app` as a peer on a free local port, and return the peer's address.
+pub async fn serve(app: Router) -> SocketAddrV4 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Unable to bind a peer");
+    let SocketAddr::V4(addr) = listener.local_addr().expect("Peer has no address") else {
+        unreachable!("Peer bound to an IPv6 address");
+    };
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
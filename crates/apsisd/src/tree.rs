@@ -0,0 +1,254 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Direct access to the ERIS block tree of a capability.
+//!
+//! `eris_rs::decode` always walks the whole tree. The helpers here decrypt
+//! individual nodes so that a byte range can be mapped onto the leaf blocks
+//! covering it, and only those blocks (plus their ancestors) are read.
+
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use eris_rs::types::{BlockStorageError, ReadCapability, Reference};
+use std::io::{self, Write};
+use std::ops::ControlFlow;
+
+/// Size of a reference-key pair in an internal node.
+const PAIR_SIZE: usize = 64;
+
+pub type Key = [u8; 32];
+
+/// Decrypt a block at the given tree level.
+pub fn decrypt(block: &[u8], key: &Key, level: u8) -> Vec<u8> {
+    let mut nonce = [0u8; 12];
+    nonce[0] = level;
+    let mut node = block.to_vec();
+    let mut cipher = ChaCha20::new(&(*key).into(), &nonce.into());
+    cipher.apply_keystream(&mut node);
+    node
+}
+
+/// Reference-key pairs of a decrypted internal node, excluding the null padding.
+pub fn children(node: &[u8]) -> Vec<(Reference, Key)> {
+    node.chunks_exact(PAIR_SIZE)
+        .take_while(|pair| pair.iter().any(|byte| *byte != 0))
+        .map(|pair| {
+            let mut reference: Reference = Default::default();
+            let mut key: Key = Default::default();
+            reference.copy_from_slice(&pair[..32]);
+            key.copy_from_slice(&pair[32..]);
+            (reference, key)
+        })
+        .collect()
+}
+
+/// Strip the mandatory `0x80 0x00*` padding from the last leaf.
+pub fn unpad(leaf: &mut Vec<u8>) -> io::Result<()> {
+    while let Some(0) = leaf.last() {
+        leaf.pop();
+    }
+    match leaf.pop() {
+        Some(0x80) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid padding in last leaf block.",
+        )),
+    }
+}
+
+/// Shape of a tree as seen from its rightmost path.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub leaves: u64,
+    pub block_size: u64,
+    pub last_leaf_len: u64,
+}
+
+impl Layout {
+    /// Size of the decoded content in bytes.
+    pub fn content_length(&self) -> u64 {
+        (self.leaves - 1) * self.block_size + self.last_leaf_len
+    }
+}
+
+pub struct Tree<'a, F> {
+    capability: &'a ReadCapability,
+    read_block: &'a F,
+}
+
+impl<'a, F> Tree<'a, F>
+where
+    F: Fn(Reference) -> Result<Vec<u8>, BlockStorageError>,
+{
+    pub fn new(capability: &'a ReadCapability, read_block: &'a F) -> Self {
+        Self {
+            capability,
+            read_block,
+        }
+    }
+
+    fn block_size(&self) -> u64 {
+        self.capability.block_size as u64
+    }
+
+    fn arity(&self) -> u64 {
+        self.block_size() / PAIR_SIZE as u64
+    }
+
+    /// Number of leaves covered by a single node at `level`.
+    fn span(&self, level: u8) -> u64 {
+        self.arity().pow(level as u32)
+    }
+
+    fn read_node(&self, reference: Reference, key: &Key, level: u8) -> io::Result<Vec<u8>> {
+        let block = (self.read_block)(reference)?;
+        if block.len() as u64 != self.block_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Block does not match capability block size.",
+            ));
+        }
+        Ok(decrypt(&block, key, level))
+    }
+
+    /// Walk the rightmost path of the tree to find the leaf count and the
+    /// unpadded length of the last leaf.
+    pub fn layout(&self) -> io::Result<Layout> {
+        let mut reference = self.capability.root_reference;
+        let mut key = self.capability.root_key;
+        let mut leaves = 0;
+        for level in (1..=self.capability.level).rev() {
+            let node = self.read_node(reference, &key, level)?;
+            let pairs = children(&node);
+            let Some(last) = pairs.last() else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Empty internal node.",
+                ));
+            };
+            leaves += (pairs.len() as u64 - 1) * self.span(level - 1);
+            (reference, key) = *last;
+        }
+        let mut leaf = self.read_node(reference, &key, 0)?;
+        unpad(&mut leaf)?;
+        Ok(Layout {
+            leaves: leaves + 1,
+            block_size: self.block_size(),
+            last_leaf_len: leaf.len() as u64,
+        })
+    }
+
+    /// Visit the references of every block of the tree, depth first with each
+    /// node before its children, so that leaves come in content order. Leaves
+    /// are never read, and the walk stops as soon as `visit` breaks.
+    pub fn references<V>(&self, visit: &mut V) -> io::Result<ControlFlow<()>>
+    where
+        V: FnMut(Reference) -> io::Result<ControlFlow<()>>,
+    {
+        let root = (self.capability.root_reference, self.capability.root_key);
+        self.visit_references(root, self.capability.level, visit)
+    }
+
+    fn visit_references<V>(
+        &self,
+        node: (Reference, Key),
+        level: u8,
+        visit: &mut V,
+    ) -> io::Result<ControlFlow<()>>
+    where
+        V: FnMut(Reference) -> io::Result<ControlFlow<()>>,
+    {
+        let (reference, key) = node;
+        if visit(reference)?.is_break() {
+            return Ok(ControlFlow::Break(()));
+        }
+        if level > 0 {
+            for child in children(&self.read_node(reference, &key, level)?) {
+                if self.visit_references(child, level - 1, visit)?.is_break() {
+                    return Ok(ControlFlow::Break(()));
+                }
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Visit the decrypted leaves with indices in `leaves.0..=leaves.1`, in order.
+    fn walk<V>(
+        &self,
+        node: (Reference, Key),
+        level: u8,
+        offset: u64,
+        leaves: (u64, u64),
+        visit: &mut V,
+    ) -> io::Result<()>
+    where
+        V: FnMut(u64, Vec<u8>) -> io::Result<()>,
+    {
+        let (reference, key) = node;
+        let decrypted = self.read_node(reference, &key, level)?;
+        if level == 0 {
+            return visit(offset, decrypted);
+        }
+        let span = self.span(level - 1);
+        for (index, child) in children(&decrypted).into_iter().enumerate() {
+            let child_first = offset + index as u64 * span;
+            if child_first + span <= leaves.0 {
+                continue;
+            }
+            if child_first > leaves.1 {
+                break;
+            }
+            self.walk(child, level - 1, child_first, leaves, visit)?;
+        }
+        Ok(())
+    }
+
+    /// Decode the inclusive byte range `start..=end` into `out`, reading only
+    /// the blocks on the paths to the covering leaves.
+    pub fn read_range<W: Write>(
+        &self,
+        layout: &Layout,
+        start: u64,
+        end: u64,
+        out: &mut W,
+    ) -> io::Result<u64> {
+        let leaves = layout.leaves;
+        let block_size = self.block_size();
+        let range = (start / block_size, (end / block_size).min(leaves - 1));
+        let mut written = 0;
+        let root = (self.capability.root_reference, self.capability.root_key);
+        self.walk(
+            root,
+            self.capability.level,
+            0,
+            range,
+            &mut |index, mut leaf| {
+                if index == leaves - 1 {
+                    unpad(&mut leaf)?;
+                }
+                let leaf_start = index * block_size;
+                let from = start.saturating_sub(leaf_start) as usize;
+                let to = ((end + 1 - leaf_start) as usize).min(leaf.len());
+                if from < to {
+                    out.write_all(&leaf[from..to])?;
+                    written += (to - from) as u64;
+                }
+                Ok(())
+            },
+        )?;
+        Ok(written)
+    }
+}
@@ -0,0 +1,703 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use eris_rs::{
+    decode::decode,
+    encode::encode,
+    types::{BlockSize, BlockStorageError, BlockWithReference, ReadCapability, Reference},
+};
+use mainline::Id;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, mpsc};
+use std::thread;
+use tokio::runtime::Handle;
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::api::ApiState;
+use crate::dht::DhtClient;
+use crate::error::{ApsisErrorKind, Result};
+use crate::metrics::QueuedAnnouncement;
+use crate::tree::Key;
+use crate::utils::{self, HashAlgorithm};
+
+/// An encoded upload.
+pub struct Encoded {
+    pub capability: ReadCapability,
+    pub bytes: u64,
+    pub blocks: usize,
+}
+
+/// Convergent uploads being encoded, keyed by a hash of their content,
+/// convergence secret and encode options, so that identical concurrent
+/// uploads are encoded and announced only once.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    slots: Arc<Mutex<HashMap<Reference, Arc<OnceCell<Arc<Encoded>>>>>>,
+}
+
+impl InFlight {
+    /// Run `encode` unless an upload with the same `id` is already in
+    /// progress, in which case wait for and share its result. If that upload
+    /// fails, one of the waiting uploads runs its own `encode` instead.
+    pub async fn coalesce<F>(&self, id: Reference, encode: F) -> Result<Arc<Encoded>>
+    where
+        F: FnOnce() -> Result<Arc<Encoded>>,
+    {
+        let slot = self
+            .slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(id)
+            .or_default()
+            .clone();
+        let encoded = slot.get_or_try_init(|| async { encode() }).await.cloned();
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        if slots
+            .get(&id)
+            .is_some_and(|current| Arc::ptr_eq(current, &slot))
+        {
+            slots.remove(&id);
+        }
+        encoded
+    }
+}
+
+/// How long the blocks of an upload are kept in the local store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// Blocks are kept indefinitely.
+    Persistent,
+    /// Blocks are removed after `expires_at` (seconds since the Unix epoch),
+    /// leaving retention to other nodes on the DHT.
+    Ephemeral { expires_at: u64 },
+}
+
+/// How an upload is encrypted and stored.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub key: Key,
+    /// Whether `key` is a convergence secret rather than a random key.
+    pub convergent: bool,
+    pub retention: Retention,
+    /// Whether to decode the upload again and compare it to the original.
+    pub verify: bool,
+}
+
+impl Options {
+    /// Persistent convergent uploads of the same content produce the same
+    /// blocks, so blocks already stored can be reused.
+    fn reuses_blocks(&self) -> bool {
+        self.convergent && self.retention == Retention::Persistent
+    }
+}
+
+/// Reader counting, and optionally hashing, the bytes passing through it.
+/// Optionally, it also keeps them while there are no more than
+/// `inline_limit`.
+struct Counted<'a, R> {
+    inner: &'a mut R,
+    count: u64,
+    hash: Option<blake2b_simd::State>,
+    inline: Option<Vec<u8>>,
+    inline_limit: u64,
+}
+
+impl<R: io::Read> io::Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        if let Some(hash) = &mut self.hash {
+            hash.update(&buf[..read]);
+        }
+        if self.count > self.inline_limit {
+            self.inline = None;
+        } else if let Some(inline) = &mut self.inline {
+            inline.extend_from_slice(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// Writer hashing decoded content.
+struct Hashing(blake2b_simd::State);
+
+impl io::Write for Hashing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn hasher() -> blake2b_simd::State {
+    HashAlgorithm::ERIS.hasher(None)
+}
+
+/// Decode a capability from the local store alone and check that the content
+/// hashes to `expected`.
+fn verify_roundtrip(
+    state: &ApiState,
+    capability: &ReadCapability,
+    expected: blake2b_simd::Hash,
+) -> Result<()> {
+    let read_block = |reference: Reference| -> std::result::Result<Vec<u8>, BlockStorageError> {
+        state
+            .store
+            .read_block(reference)
+            .ok()
+            .flatten()
+            .ok_or_else(|| io::Error::other("Block missing from database."))
+    };
+    let capability = utils::copy_capability(capability);
+    let mut decoded = Hashing(hasher());
+    decode(capability, &mut decoded, &read_block)
+        .map_err(|err| ApsisErrorKind::Verify(err.to_string()))?;
+    if decoded.0.finalize() != expected {
+        return Err(ApsisErrorKind::Verify(
+            "Decoded content does not match the upload.".to_owned(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Bookkeeping shared between the encoder and the storage workers.
+#[derive(Default)]
+struct Progress {
+    blocks: AtomicUsize,
+    /// Blocks added to the store by this upload.
+    written: Mutex<Vec<Reference>>,
+    /// Blocks of this upload that were already stored.
+    reused: Mutex<Vec<Reference>>,
+    /// Blocks pinned by this upload.
+    pinned: Mutex<Vec<Reference>>,
+}
+
+impl Progress {
+    /// Count a block produced by the encoder, failing past `max_blocks`.
+    fn count(&self, max_blocks: usize) -> std::result::Result<(), BlockStorageError> {
+        if self.blocks.fetch_add(1, Ordering::Relaxed) >= max_blocks {
+            return Err(io::Error::other(
+                "Upload exceeds the maximum number of blocks.",
+            ));
+        }
+        Ok(())
+    }
+
+    fn exceeded(&self, max_blocks: usize) -> bool {
+        self.blocks.load(Ordering::Relaxed) > max_blocks
+    }
+
+    /// References of the blocks written or reused so far.
+    fn stored(&self) -> Result<Vec<Reference>> {
+        let (Ok(written), Ok(reused)) = (self.written.lock(), self.reused.lock()) else {
+            return Err(ApsisErrorKind::Database("Block list poisoned.".to_owned()).into());
+        };
+        Ok(written.iter().chain(reused.iter()).copied().collect())
+    }
+}
+
+/// Store a block and announce it on the DHT in the background, unless
+/// announcements are deferred until the upload completes. Ephemeral blocks
+/// are announced before returning, failing the upload if the DHT doesn't
+/// accept the announcement.
+fn store_block(
+    state: &ApiState,
+    runtime: &Handle,
+    progress: &Progress,
+    options: &Options,
+    block: BlockWithReference,
+) -> std::result::Result<usize, BlockStorageError> {
+    // Blocks already stored, whether left by an earlier attempt at a
+    // convergent upload or cached from another node, were announced when
+    // they were stored, so they only need pinning
+    if options.reuses_blocks()
+        && state
+            .store
+            .pin_block(block.reference)
+            .map_err(|_err| io::Error::other("Failed to pin block in database."))?
+    {
+        if let Ok(mut pinned) = progress.pinned.lock() {
+            pinned.push(block.reference);
+        }
+        if let Ok(mut reused) = progress.reused.lock() {
+            reused.push(block.reference);
+        }
+        return Ok(block.block.len());
+    }
+
+    let retention = options.retention;
+    let (reference, length) = (block.reference, block.block.len());
+    let added = match retention {
+        Retention::Persistent => state.store.write_block(reference, block.block),
+        Retention::Ephemeral { expires_at } => {
+            state
+                .store
+                .write_expiring_block(reference, block.block, expires_at)
+        }
+    }
+    .map_err(|_err| io::Error::other("Failed to write block to database."))?;
+    if retention == Retention::Persistent
+        && let Ok(mut pinned) = progress.pinned.lock()
+    {
+        pinned.push(reference);
+    }
+    // Only blocks added here are removed again if the upload fails
+    let stored = if added {
+        &progress.written
+    } else {
+        &progress.reused
+    };
+    if let Ok(mut stored) = stored.lock() {
+        stored.push(reference);
+    }
+    if state.defer_announce {
+        return Ok(length);
+    }
+    let id = utils::try_ref_to_id(&reference).map_err(|err| io::Error::other(err.to_string()))?;
+    if retention != Retention::Persistent {
+        announce(
+            &**state.dht.current(),
+            &state.announce_permits,
+            runtime,
+            id,
+            state.port,
+        )
+        .map_err(|_err| io::Error::other("Failed to announce block peer."))?;
+        return Ok(length);
+    }
+    let Some(port) = state.port else {
+        return Ok(length);
+    };
+    let dht = state.dht.current();
+    let permits = state.announce_permits.clone();
+    state.tracker.spawn_on(
+        async move {
+            let _permit = announce_permit(permits).await;
+            let _ = dht
+                .announce_peer(id, Some(port))
+                .map_err(|_err| io::Error::other("Failed to announce block peer."));
+        },
+        runtime,
+    );
+    Ok(length)
+}
+
+/// Wait for one of the announcement permits shared by all requests, counting
+/// the wait in the announcement queue metric.
+pub async fn announce_permit(permits: Arc<Semaphore>) -> Option<OwnedSemaphorePermit> {
+    let _queued = QueuedAnnouncement::start();
+    permits.acquire_owned().await.ok()
+}
+
+/// Announce `id` on `port` once an announcement permit is free. Fails
+/// without a port to advertise, as peers couldn't fetch the block. Must be
+/// called from a blocking context.
+pub fn announce(
+    dht: &dyn DhtClient,
+    permits: &Arc<Semaphore>,
+    runtime: &Handle,
+    id: Id,
+    port: Option<u16>,
+) -> Result<()> {
+    let Some(port) = port else {
+        return Err(ApsisErrorKind::Announce("No port to advertise.".to_owned()).into());
+    };
+    let _permit = runtime.block_on(announce_permit(permits.clone()));
+    dht.announce_peer(id, Some(port))?;
+    Ok(())
+}
+
+/// Announce all blocks of a completed upload. Ephemeral blocks are announced
+/// before returning, persistent ones in the background.
+fn announce_written(state: &ApiState, retention: Retention, progress: &Progress) -> Result<()> {
+    // Reused blocks may come from an attempt that failed before announcing
+    let ids = progress
+        .stored()?
+        .iter()
+        .map(utils::try_ref_to_id)
+        .collect::<Result<Vec<_>>>()?;
+    let runtime = Handle::current();
+    let dht = state.dht.current();
+    if retention != Retention::Persistent {
+        for id in ids {
+            announce(&**dht, &state.announce_permits, &runtime, id, state.port)?;
+        }
+        return Ok(());
+    }
+    let Some(port) = state.port else {
+        return Ok(());
+    };
+    let permits = state.announce_permits.clone();
+    state.tracker.spawn_blocking_on(
+        {
+            let runtime = runtime.clone();
+            move || {
+                for id in ids {
+                    if let Err(err) = announce(&**dht, &permits, &runtime, id, Some(port)) {
+                        warn!("Failed to announce block peer: {}", err);
+                    }
+                }
+            }
+        },
+        &runtime,
+    );
+    Ok(())
+}
+
+/// Drop the pins of a failed upload and remove the blocks it added, unless
+/// another upload pins them.
+fn rollback(state: &ApiState, progress: Progress) {
+    for reference in progress.pinned.into_inner().unwrap_or_default() {
+        if let Err(err) = state.store.unpin_block(reference) {
+            warn!("Failed to unpin block: {}", err);
+        }
+    }
+    for reference in progress.written.into_inner().unwrap_or_default() {
+        if let Err(err) = state.store.delete_unpinned_block(reference) {
+            warn!("Failed to roll back block: {}", err);
+        }
+    }
+}
+
+/// Run the encoder, handing blocks to `write_workers` storage workers over a
+/// bounded channel when configured, so that storage and announcement overlap
+/// with encryption and hashing of the following blocks.
+fn run_encoder<R: io::Read>(
+    state: &ApiState,
+    content: &mut R,
+    block_size: BlockSize,
+    options: &Options,
+    progress: &Progress,
+) -> std::result::Result<ReadCapability, String> {
+    let runtime = Handle::current();
+    if state.write_workers == 0 {
+        let write_block = |block| {
+            progress.count(state.max_blocks)?;
+            store_block(state, &runtime, progress, options, block)
+        };
+        return encode(content, &options.key, block_size, &write_block)
+            .map_err(|err| err.to_string());
+    }
+
+    let (tx, rx) = mpsc::sync_channel::<BlockWithReference>(state.write_queue);
+    let rx = Mutex::new(rx);
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..state.write_workers)
+            .map(|_| {
+                scope.spawn(|| -> std::result::Result<(), BlockStorageError> {
+                    loop {
+                        let block = match rx.lock() {
+                            Ok(rx) => rx.recv(),
+                            Err(_) => return Err(io::Error::other("Block queue poisoned.")),
+                        };
+                        match block {
+                            Ok(block) => store_block(state, &runtime, progress, options, block)?,
+                            // The encoder has finished and the queue is drained
+                            Err(_) => return Ok(()),
+                        };
+                    }
+                })
+            })
+            .collect();
+
+        let write_block =
+            move |block: BlockWithReference| -> std::result::Result<usize, BlockStorageError> {
+                progress.count(state.max_blocks)?;
+                let length = block.block.len();
+                tx.send(block)
+                    .map_err(|_err| io::Error::other("Block writers stopped."))?;
+                Ok(length)
+            };
+        let encoded = encode(content, &options.key, block_size, &write_block);
+        drop(write_block);
+
+        let mut stored = Ok(());
+        for worker in workers {
+            let res = worker
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("Block writer panicked.")));
+            stored = stored.and(res);
+        }
+        let capability = encoded.map_err(|err| err.to_string())?;
+        stored.map_err(|err| err.to_string())?;
+        Ok(capability)
+    })
+}
+
+/// Encode content into blocks, returning its capability and size. Blocks
+/// the upload added are removed again if it fails or doesn't decode back
+/// to the original when verified, unless a retry of the same convergent
+/// upload can reuse them. With deferred announcements only the blocks of
+/// successful uploads are announced.
+///
+/// Must be called from a blocking context.
+pub fn encode_content<R: io::Read>(
+    state: &ApiState,
+    content: &mut R,
+    block_size: BlockSize,
+    options: &Options,
+) -> Result<Encoded> {
+    let mut content = Counted {
+        inner: content,
+        count: 0,
+        hash: options.verify.then(hasher),
+        inline: state.inline_max_bytes.map(|_| Vec::new()),
+        inline_limit: state.inline_max_bytes.unwrap_or_default(),
+    };
+    let progress = Progress::default();
+    match run_encoder(state, &mut content, block_size, options, &progress) {
+        Ok(capability) => {
+            if let Some(hash) = &content.hash
+                && let Err(err) = verify_roundtrip(state, &capability, hash.finalize())
+            {
+                rollback(state, progress);
+                return Err(err);
+            }
+            if state.defer_announce
+                && let Err(err) = announce_written(state, options.retention, &progress)
+            {
+                rollback(state, progress);
+                return Err(err);
+            }
+            if let Err(err) = progress
+                .stored()
+                .and_then(|blocks| state.store.add_owner(capability.root_reference, &blocks))
+            {
+                warn!("Failed to index the blocks of an upload: {}", err);
+            }
+            if let Some(inline) = &content.inline
+                && let Err(err) = state.store.cache_inline(
+                    capability.root_reference,
+                    &capability.root_key,
+                    inline,
+                    state.inline_cache_bytes,
+                )
+            {
+                warn!("Failed to cache the content of an upload inline: {}", err);
+            }
+            Ok(Encoded {
+                capability,
+                bytes: content.count,
+                blocks: progress.blocks.into_inner(),
+            })
+        }
+        Err(err) => {
+            // Keep the blocks of a failed convergent upload for a retry to
+            // reuse, unless retrying can't succeed
+            let exceeded = progress.exceeded(state.max_blocks);
+            if exceeded || !options.reuses_blocks() {
+                rollback(state, progress);
+            }
+            if exceeded {
+                Err(ApsisErrorKind::TooManyBlocks(state.max_blocks).into())
+            } else {
+                Err(ApsisErrorKind::Encode(err).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::GcPolicy;
+    use crate::testing::{self, TestNode};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use tokio::task;
+
+    /// Random content, so that no two of its blocks are the same.
+    fn random_content(length: usize) -> Vec<u8> {
+        (0..length).map(|_| rand::random()).collect()
+    }
+
+    fn options(retention: Retention) -> Options {
+        Options {
+            key: rand::random(),
+            convergent: false,
+            retention,
+            verify: false,
+        }
+    }
+
+    /// Encode `content` as an upload would.
+    async fn upload(state: &ApiState, content: Vec<u8>, options: Options) -> Result<Encoded> {
+        let state = state.clone();
+        task::spawn_blocking(move || {
+            encode_content(&state, &mut &content[..], BlockSize::Size1KiB, &options)
+        })
+        .await
+        .expect("Upload panicked")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn announces_every_block_on_the_node_port() {
+        let node = TestNode::new();
+        let encoded = upload(
+            &node.state,
+            random_content(64 * 1024),
+            options(Retention::Persistent),
+        )
+        .await
+        .unwrap();
+        node.settle().await;
+
+        let references = stored(&node);
+        assert_eq!(references.len(), encoded.blocks);
+        let peer = SocketAddrV4::new(Ipv4Addr::LOCALHOST, testing::PORT);
+        for reference in references {
+            let id = utils::try_ref_to_id(&reference).unwrap();
+            assert_eq!(node.dht.peers(id), [peer]);
+        }
+    }
+
+    fn stored(node: &TestNode) -> Vec<Reference> {
+        node.state
+            .store
+            .iter_references()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_upload_removes_its_blocks() {
+        let node = TestNode::new();
+        let mut state = node.state.clone();
+        state.max_blocks = 4;
+        let failed = upload(
+            &state,
+            random_content(64 * 1024),
+            options(Retention::Persistent),
+        )
+        .await;
+
+        assert!(failed.is_err());
+        assert!(stored(&node).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_ephemeral_upload_keeps_pinned_blocks() {
+        let node = TestNode::new();
+        let content = random_content(64 * 1024);
+        let persistent = options(Retention::Persistent);
+        upload(&node.state, content.clone(), persistent)
+            .await
+            .unwrap();
+        let before = stored(&node);
+
+        // The same key yields the same blocks, all of them already stored
+        let mut state = node.state.clone();
+        state.max_blocks = 4;
+        let ephemeral = Options {
+            retention: Retention::Ephemeral {
+                expires_at: utils::unix_time() + 60,
+            },
+            ..persistent
+        };
+        assert!(upload(&state, content, ephemeral).await.is_err());
+        assert_eq!(stored(&node), before);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn convergent_upload_pins_cached_blocks() {
+        let content = random_content(64 * 1024);
+        let convergent = Options {
+            convergent: true,
+            ..options(Retention::Persistent)
+        };
+        let origin = TestNode::new();
+        upload(&origin.state, content.clone(), convergent)
+            .await
+            .unwrap();
+
+        // Another node cached the blocks before the same content is uploaded
+        let node = TestNode::new();
+        for block in origin.state.store.iter_blocks() {
+            let (reference, block) = block.unwrap();
+            node.state.store.cache_block(reference, block).unwrap();
+        }
+        upload(&node.state, content, convergent).await.unwrap();
+
+        let policy = GcPolicy {
+            max_block_age: None,
+            max_store_bytes: Some(1),
+        };
+        let reclaimed = node
+            .state
+            .store
+            .collect_garbage(policy, utils::unix_time(), false)
+            .unwrap();
+        assert_eq!(reclaimed.blocks, 0);
+        assert_eq!(stored(&node), stored(&origin));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn collection_under_pressure_keeps_every_uploaded_block() {
+        let node = TestNode::new();
+        let encoded = upload(
+            &node.state,
+            random_content(64 * 1024),
+            options(Retention::Persistent),
+        )
+        .await
+        .unwrap();
+        let uploaded = stored(&node);
+        assert_eq!(uploaded.len(), encoded.blocks);
+
+        // Unrelated blocks cached from other nodes
+        for _ in 0..16 {
+            let block = random_content(1024);
+            let reference = HashAlgorithm::ERIS.hash(&block, None);
+            node.state.store.cache_block(reference, block).unwrap();
+        }
+
+        // The store is over its limit with the cached blocks alone, and
+        // pinned blocks can't bring it back under
+        let policy = GcPolicy {
+            max_block_age: None,
+            max_store_bytes: Some(encoded.blocks as u64 * 1024),
+        };
+        let reclaimed = node
+            .state
+            .store
+            .collect_garbage(policy, utils::unix_time(), false)
+            .unwrap();
+        assert_eq!(reclaimed.blocks, 16);
+        assert_eq!(stored(&node), uploaded);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ephemeral_upload_fails_without_a_port() {
+        let node = TestNode::new();
+        let mut state = node.state.clone();
+        state.port = None;
+        let ephemeral = options(Retention::Ephemeral {
+            expires_at: utils::unix_time() + 60,
+        });
+        assert!(
+            upload(&state, random_content(4 * 1024), ephemeral)
+                .await
+                .is_err()
+        );
+        assert!(stored(&node).is_empty());
+    }
+}
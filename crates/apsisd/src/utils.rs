@@ -15,37 +15,105 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::net::SocketAddrV4;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use base32;
 use blake2b_simd::Params;
-use eris_rs::types::Reference;
-use mainline::{Dht, Id, errors::DecodeIdError};
+use eris_rs::types::{ReadCapability, Reference};
+use mainline::{Id, errors::DecodeIdError};
 use reqwest;
+use serde::Deserialize;
+use tokio::sync::mpsc;
 
+use crate::dht::DhtClient;
 use crate::error::{ApsisErrorKind, Result};
+use crate::metrics::Failure;
 
 const MAX_PEER_RETRIES: usize = 3;
 
+/// Longest a single peer is given to answer a block request.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the largest ERIS block, beyond which a peer's response isn't read.
+const MAX_BLOCK_SIZE: u64 = 32 * 1024;
+
+/// Client shared by all block fetches, so that connections to peers are
+/// pooled and reused.
+static PEER_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
 pub fn try_ref_to_id(reference: &Reference) -> Result<Id> {
     let id = Id::from_bytes(&reference[..20]).map_err(|err| DecodeIdError::InvalidIdSize(err))?;
     Ok(id)
 }
 
-pub fn urn_to_ref(urn: String) -> Option<Reference> {
-    let base32_alphabet = base32::Alphabet::Rfc4648 { padding: false };
-    match urn.split_once("urn:") {
-        Some((_, reference_base32)) => match base32::decode(base32_alphabet, reference_base32) {
-            Some(bytes) => bytes.try_into().ok(),
-            None => None,
-        },
-        None => None,
+/// Textual encoding of the reference in the `urn:<reference>` form of a single
+/// block. ERIS URNs of capabilities are always base32.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RefEncoding {
+    #[default]
+    Base32,
+    Base58,
+    Hex,
+}
+
+impl RefEncoding {
+    pub fn encode(&self, reference: &Reference) -> String {
+        match self {
+            Self::Base32 => base32::encode(base32::Alphabet::Rfc4648 { padding: false }, reference),
+            Self::Base58 => bs58::encode(reference).into_string(),
+            Self::Hex => hex::encode(reference),
+        }
+    }
+
+    pub fn decode(&self, encoded: &str) -> Option<Reference> {
+        let bytes = match self {
+            Self::Base32 => base32::decode(base32::Alphabet::Rfc4648 { padding: false }, encoded),
+            Self::Base58 => bs58::decode(encoded).into_vec().ok(),
+            Self::Hex => hex::decode(encoded).ok(),
+        };
+        bytes?.try_into().ok()
+    }
+}
+
+/// A copy of a capability, which doesn't implement `Clone`.
+pub fn copy_capability(capability: &ReadCapability) -> ReadCapability {
+    ReadCapability {
+        root_reference: capability.root_reference,
+        root_key: capability.root_key,
+        level: capability.level,
+        block_size: capability.block_size,
     }
 }
 
-fn ref_to_urn(reference: &Reference) -> String {
-    let base32_alphabet = base32::Alphabet::Rfc4648 { padding: false };
-    let block_ref = base32::encode(base32_alphabet, reference);
-    "urn:".to_owned() + &block_ref
+pub fn urn_to_ref(urn: String) -> Option<Reference> {
+    urn_to_ref_as(urn, RefEncoding::Base32)
+}
+
+pub fn urn_to_ref_as(urn: String, encoding: RefEncoding) -> Option<Reference> {
+    let (_, encoded) = urn.split_once("urn:")?;
+    encoding.decode(encoded)
+}
+
+pub fn ref_to_urn(reference: &Reference) -> String {
+    ref_to_urn_as(reference, RefEncoding::Base32)
+}
+
+pub fn ref_to_urn_as(reference: &Reference, encoding: RefEncoding) -> String {
+    "urn:".to_owned() + &encoding.encode(reference)
+}
+
+/// A URN safe to log: an ERIS URN is reduced to its root reference, leaving
+/// out the key, and anything else that looks like one is left out entirely.
+pub fn redact_urn(urn: &str) -> String {
+    if let Some(capability) = ReadCapability::from_urn(urn.to_owned()) {
+        ref_to_urn(&capability.root_reference)
+    } else if urn.to_ascii_lowercase().contains("eris") {
+        "[redacted]".to_owned()
+    } else {
+        urn.to_owned()
+    }
 }
 
 fn peer_to_url(peer: SocketAddrV4, block: &Reference) -> String {
@@ -57,38 +125,135 @@ fn peer_to_url(peer: SocketAddrV4, block: &Reference) -> String {
     )
 }
 
-fn blake2b256_hash(input: &[u8], key: Option<&[u8]>) -> Reference {
-    let mut hasher = match key {
-        Some(k) => Params::new().hash_length(32).key(k).to_state(),
-        None => Params::new().hash_length(32).to_state(),
-    };
-    hasher.update(input);
-    let mut result: Reference = Default::default();
-    result.copy_from_slice(hasher.finalize().as_bytes());
-    result
+/// Hash function computing block references. ERIS mandates BLAKE2b-256, so
+/// it is the only variant, but every integrity check goes through it so that
+/// verification always matches how references were computed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Blake2b256,
 }
 
-pub fn fetch_block(reference: [u8; 32], dht: &Dht, check: bool) -> Result<Vec<u8>> {
+impl HashAlgorithm {
+    /// The algorithm ERIS computes block references with.
+    pub const ERIS: Self = Self::Blake2b256;
+
+    /// Incremental hasher, keyed with `key` if given.
+    pub fn hasher(&self, key: Option<&[u8]>) -> blake2b_simd::State {
+        match self {
+            Self::Blake2b256 => {
+                let mut params = Params::new();
+                params.hash_length(32);
+                if let Some(key) = key {
+                    params.key(key);
+                }
+                params.to_state()
+            }
+        }
+    }
+
+    /// Hash `input`, keyed with `key` if given.
+    pub fn hash(&self, input: &[u8], key: Option<&[u8]>) -> Reference {
+        let mut hasher = self.hasher(key);
+        hasher.update(input);
+        let mut result: Reference = Default::default();
+        result.copy_from_slice(hasher.finalize().as_bytes());
+        result
+    }
+
+    /// Whether `block` is the block `reference` refers to.
+    pub fn verify(&self, block: &[u8], reference: &Reference) -> bool {
+        self.hash(block, None) == *reference
+    }
+}
+
+/// Peers announcing `id`, looked up on the blocking pool and received in
+/// batches as the lookup progresses. The lookup stops at the next batch once
+/// the receiver is dropped.
+fn peer_batches(dht: Arc<Box<dyn DhtClient>>, id: Id) -> mpsc::Receiver<Vec<SocketAddrV4>> {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::task::spawn_blocking(move || {
+        for peers in dht.get_peers(id) {
+            if tx.blocking_send(peers).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Request a block from a peer, reading at most one byte more than the
+/// largest block.
+async fn fetch_candidate(
+    client: &reqwest::Client,
+    peer: SocketAddrV4,
+    reference: &Reference,
+    timeout: Duration,
+) -> reqwest::Result<Vec<u8>> {
+    let mut response = client
+        .get(peer_to_url(peer, reference))
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut candidate = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        candidate.extend_from_slice(&chunk);
+        if candidate.len() as u64 > MAX_BLOCK_SIZE {
+            candidate.truncate(MAX_BLOCK_SIZE as usize + 1);
+            break;
+        }
+    }
+    Ok(candidate)
+}
+
+/// Fetch a block from peers announcing it. With a `deadline`, no peer is given
+/// longer than what remains of it, and no new retry round or peer request is
+/// started once it has passed.
+pub async fn fetch_block(
+    reference: [u8; 32],
+    dht: Arc<Box<dyn DhtClient>>,
+    check: bool,
+    deadline: Option<Instant>,
+) -> Result<Vec<u8>> {
     if !dht.bootstrapped() {
-        return Err(ApsisErrorKind::BlockNotFound("DHT failed to bootstrap.".to_owned()).into());
+        return Err(ApsisErrorKind::DhtNotReady("DHT failed to bootstrap.".to_owned()).into());
     }
 
     let id = try_ref_to_id(&reference)?;
-    let client = reqwest::blocking::Client::new();
+
+    let remaining = || match deadline {
+        Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+        None => PEER_TIMEOUT,
+    };
+    let deadline_exceeded =
+        || ApsisErrorKind::BlockNotFound("Read deadline exceeded.".to_owned()).into();
 
     let mut tries = 0;
     while tries < MAX_PEER_RETRIES {
-        let subset = dht.get_peers(id);
-        for peers in subset {
+        if remaining().is_zero() {
+            return Err(deadline_exceeded());
+        }
+        let mut batches = peer_batches(dht.clone(), id);
+        while let Some(peers) = batches.recv().await {
             for peer in peers {
-                let candidate = client.get(peer_to_url(peer, &reference)).send()?.bytes()?;
-                if check {
-                    let hash = blake2b256_hash(candidate.as_ref(), None);
-                    if hash != reference {
-                        continue;
-                    }
+                let timeout = PEER_TIMEOUT.min(remaining());
+                if timeout.is_zero() {
+                    return Err(deadline_exceeded());
+                }
+                // A peer that doesn't answer in time is skipped like an invalid one
+                let Ok(candidate) = fetch_candidate(&PEER_CLIENT, peer, &reference, timeout).await
+                else {
+                    continue;
+                };
+                if !matches!(candidate.len(), 1024 | 32768) {
+                    Failure::IntegrityFailure.record();
+                    continue;
                 }
-                return Ok(candidate.into());
+                if check && !HashAlgorithm::ERIS.verify(&candidate, &reference) {
+                    Failure::IntegrityFailure.record();
+                    continue;
+                }
+                return Ok(candidate);
             }
         }
         tries += 1;
@@ -96,3 +261,190 @@ pub fn fetch_block(reference: [u8; 32], dht: &Dht, check: bool) -> Result<Vec<u8
 
     Err(ApsisErrorKind::BlockNotFound("Failed to fetch valid block.".to_owned()).into())
 }
+
+/// Seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::mock::MockDht;
+    use crate::testing;
+    use axum::{Router, http::StatusCode, routing::get};
+
+    /// A random block of the smallest ERIS block size, and its reference.
+    fn random_block() -> (Reference, Vec<u8>) {
+        let block: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+        (HashAlgorithm::ERIS.hash(&block, None), block)
+    }
+
+    fn client(dht: MockDht) -> Arc<Box<dyn DhtClient>> {
+        Arc::new(Box::new(dht))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fetches_blocks_from_announcing_peers() {
+        let (reference, block) = random_block();
+        let peer = testing::serve_blocks(vec![(reference, block.clone())]).await;
+        let dht = MockDht::default();
+        dht.add_peer(try_ref_to_id(&reference).unwrap(), peer);
+
+        let fetched = fetch_block(reference, client(dht), true, None)
+            .await
+            .unwrap();
+        assert_eq!(fetched, block);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn skips_peers_serving_the_wrong_block() {
+        let (reference, block) = random_block();
+        let (_, other) = random_block();
+        let bad = testing::serve_blocks(vec![(reference, other)]).await;
+        let good = testing::serve_blocks(vec![(reference, block.clone())]).await;
+        let dht = MockDht::default();
+        let id = try_ref_to_id(&reference).unwrap();
+        dht.add_peer(id, bad);
+        dht.add_peer(id, good);
+
+        let fetched = fetch_block(reference, client(dht), true, None)
+            .await
+            .unwrap();
+        assert_eq!(fetched, block);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fails_without_a_valid_peer() {
+        let (reference, _) = random_block();
+        let (_, other) = random_block();
+        let bad = testing::serve_blocks(vec![(reference, other)]).await;
+        let dht = MockDht::default();
+        dht.add_peer(try_ref_to_id(&reference).unwrap(), bad);
+
+        let err = fetch_block(reference, client(dht), true, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.inner(), ApsisErrorKind::BlockNotFound(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn skips_peers_answering_an_error() {
+        let (reference, _) = random_block();
+        // An error page the size of a block, taken for one when unchecked
+        let app = Router::new().route(
+            "/uri-res/N2R",
+            get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, vec![0; 1024]) }),
+        );
+        let dht = MockDht::default();
+        dht.add_peer(
+            try_ref_to_id(&reference).unwrap(),
+            testing::serve(app).await,
+        );
+
+        let err = fetch_block(reference, client(dht), false, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.inner(), ApsisErrorKind::BlockNotFound(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reports_an_unbootstrapped_dht() {
+        let (reference, _) = random_block();
+        let err = fetch_block(reference, client(MockDht::unbootstrapped()), true, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.inner(), ApsisErrorKind::DhtNotReady(_)));
+    }
+
+    #[test]
+    fn eris_hash_is_blake2b_256() {
+        // Test vectors of BLAKE2b with a 32 byte digest
+        assert_eq!(
+            hex::encode(HashAlgorithm::ERIS.hash(b"", None)),
+            "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8"
+        );
+        assert_eq!(
+            hex::encode(HashAlgorithm::ERIS.hash(b"abc", None)),
+            "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319"
+        );
+    }
+
+    #[test]
+    fn incremental_hashes_match() {
+        let key = [7; 32];
+        let mut hasher = HashAlgorithm::ERIS.hasher(Some(&key));
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert_eq!(
+            hasher.finalize().as_bytes(),
+            HashAlgorithm::ERIS.hash(b"abc", Some(&key))
+        );
+        assert_ne!(
+            HashAlgorithm::ERIS.hash(b"abc", Some(&key)),
+            HashAlgorithm::ERIS.hash(b"abc", None)
+        );
+    }
+
+    #[test]
+    fn verifies_blocks_against_their_reference() {
+        let (reference, mut block) = random_block();
+        assert!(HashAlgorithm::ERIS.verify(&block, &reference));
+        block[0] ^= 1;
+        assert!(!HashAlgorithm::ERIS.verify(&block, &reference));
+    }
+
+    #[test]
+    fn references_round_trip_every_encoding() {
+        let (reference, _) = random_block();
+        for encoding in [RefEncoding::Base32, RefEncoding::Base58, RefEncoding::Hex] {
+            let urn = ref_to_urn_as(&reference, encoding);
+            assert_eq!(urn_to_ref_as(urn, encoding), Some(reference));
+            assert_eq!(
+                encoding.decode(&encoding.encode(&reference)),
+                Some(reference)
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_references_as_documented() {
+        let reference = [0xab; 32];
+        assert_eq!(RefEncoding::Hex.encode(&reference), "ab".repeat(32));
+        assert!(
+            RefEncoding::Base32
+                .encode(&reference)
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || ('2'..='7').contains(&c))
+        );
+        // Leading zero bytes are kept by base58 as leading ones
+        let mut reference = [0xab; 32];
+        reference[0] = 0;
+        assert!(RefEncoding::Base58.encode(&reference).starts_with('1'));
+    }
+
+    #[test]
+    fn rejects_references_of_the_wrong_length() {
+        for encoding in [RefEncoding::Base32, RefEncoding::Base58, RefEncoding::Hex] {
+            assert_eq!(encoding.decode(&encoding.encode(&[1; 32])[1..]), None);
+        }
+        assert_eq!(RefEncoding::Hex.decode(&"ab".repeat(31)), None);
+        assert_eq!(RefEncoding::Hex.decode("not hex"), None);
+    }
+
+    #[test]
+    fn peer_urls_use_the_announced_port() {
+        let (reference, _) = random_block();
+        let peer = SocketAddrV4::new([192, 0, 2, 1].into(), 8443);
+        assert_eq!(
+            peer_to_url(peer, &reference),
+            format!(
+                "http://192.0.2.1:8443/uri-res/N2R?{}",
+                ref_to_urn(&reference)
+            )
+        );
+    }
+}
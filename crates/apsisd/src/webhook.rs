@@ -0,0 +1,140 @@
+// Apsis
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+use crate::upload::Encoded;
+use crate::utils;
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const SIGNATURE_HEADER: &str = "X-Apsis-Signature";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookEvent {
+    Upload,
+}
+
+fn all_events() -> Vec<WebhookEvent> {
+    vec![WebhookEvent::Upload]
+}
+
+/// A webhook endpoint and the events it subscribes to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default = "all_events")]
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    event: WebhookEvent,
+    urn: &'a str,
+    bytes: u64,
+    blocks: usize,
+    timestamp: u64,
+}
+
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    hooks: Arc<Vec<Webhook>>,
+    secret: Option<Arc<String>>,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>, secret: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            hooks: Arc::new(hooks),
+            secret: secret.map(Arc::new),
+        }
+    }
+
+    /// Notify subscribers of a successful upload in the background.
+    pub fn notify_upload(&self, tracker: &TaskTracker, encoded: &Encoded) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let timestamp = utils::unix_time();
+        let urn = encoded.capability.to_urn();
+        let notification = Notification {
+            event: WebhookEvent::Upload,
+            urn: &urn,
+            bytes: encoded.bytes,
+            blocks: encoded.blocks,
+            timestamp,
+        };
+        let Ok(body) = serde_json::to_string(&notification) else {
+            return;
+        };
+        for hook in self
+            .hooks
+            .iter()
+            .filter(|hook| hook.events.contains(&WebhookEvent::Upload))
+        {
+            tracker.spawn(self.clone().deliver(hook.url.clone(), body.clone()));
+        }
+    }
+
+    fn sign(&self, body: &str) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+        mac.update(body.as_bytes());
+        Some(format!(
+            "sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+
+    /// POST the notification, retrying with exponential backoff.
+    async fn deliver(self, url: String, body: String) {
+        let signature = self.sign(&body);
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut req = self
+                .client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                req = req.header(SIGNATURE_HEADER, signature);
+            }
+            match req.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => {
+                    debug!("Delivered webhook to {}", url);
+                    return;
+                }
+                Err(err) => warn!(
+                    "Webhook delivery to {} failed (attempt {}/{}): {}",
+                    url, attempt, MAX_ATTEMPTS, err
+                ),
+            }
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+}